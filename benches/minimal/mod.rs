@@ -0,0 +1,298 @@
+//! A minimal subscriber which is generic over the slab its spans are stored
+//! in, so that slabs can be compared on their own.
+//!
+//! Only what affects contention is kept: each slot records its span's fields
+//! as a string, spans are entered on a thread-local stack, and events are
+//! ignored. Everything but the slab is shared, so any difference between two
+//! `Minimal` subscribers comes from their slabs.
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::{self, Write},
+    hint,
+    sync::{
+        atomic::{self, AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Id, Metadata, Subscriber,
+};
+use tracing_concat::__bench::Slab;
+
+/// A span slab.
+pub trait Slots: Default + Send + Sync + 'static {
+    /// Stores `slot` in an empty slot, returning its index.
+    fn insert(&self, slot: Slot) -> usize;
+
+    /// Calls `f` with the slot at `idx`, read-locked.
+    fn read<R>(&self, idx: usize, f: impl FnOnce(&Slot) -> R) -> Option<R>;
+
+    /// Calls `f` with the slot at `idx`, write-locked.
+    fn write<R>(&self, idx: usize, f: impl FnOnce(&mut Slot) -> R) -> Option<R>;
+
+    /// Empties the slot at `idx` and frees it, returning its span.
+    fn remove(&self, idx: usize) -> Option<Data>;
+}
+
+#[derive(Default)]
+pub struct Minimal<S> {
+    slots: S,
+}
+
+#[derive(Default)]
+pub struct Slot {
+    fields: String,
+    span: Option<Data>,
+}
+
+pub struct Data {
+    parent: Option<Id>,
+    ref_count: AtomicUsize,
+}
+
+/// The slab as it was before the paged slab.
+///
+/// It is a `Vec` of slots behind a global `RwLock`, which has to be
+/// write-locked whenever the slab grows, and its free list is a Treiber stack
+/// of slot indices without ABA protection.
+#[derive(Default)]
+pub struct VecSlab {
+    slab: RwLock<Vec<RwLock<VecSlot>>>,
+    // The head of the slab's free list.
+    next: AtomicUsize,
+}
+
+struct VecSlot {
+    slot: Slot,
+    // The next slot on the free list, while this one is empty.
+    next: usize,
+}
+
+struct Stack {
+    stack: Vec<(Id, bool)>,
+    ids: HashSet<Id>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Stack> = RefCell::new(Stack {
+        stack: Vec::new(),
+        ids: HashSet::new(),
+    });
+}
+
+// ===== impl Minimal =====
+
+impl<S: Slots> Minimal<S> {
+    fn current(&self) -> Option<Id> {
+        CONTEXT
+            .try_with(|current| {
+                let current = current.borrow();
+                let (id, _) = current.stack.iter().rev().find(|(_, dup)| !dup)?;
+                Some(id.clone())
+            })
+            .ok()?
+    }
+}
+
+impl<S: Slots> Subscriber for Minimal<S> {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> Id {
+        let parent = if attrs.is_root() {
+            None
+        } else if attrs.is_contextual() {
+            self.current().map(|id| self.clone_span(&id))
+        } else {
+            attrs.parent().map(|id| self.clone_span(id))
+        };
+        let mut slot = Slot {
+            fields: String::new(),
+            span: Some(Data {
+                parent,
+                ref_count: AtomicUsize::new(1),
+            }),
+        };
+        attrs.record(&mut Recorder(&mut slot.fields));
+        Id::from_u64(self.slots.insert(slot) as u64 + 1)
+    }
+
+    fn record(&self, id: &Id, values: &span::Record<'_>) {
+        self.slots.write(id.into_u64() as usize - 1, |slot| {
+            values.record(&mut Recorder(&mut slot.fields))
+        });
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, id: &Id) {
+        let id = self.clone_span(id);
+        let _ = CONTEXT.try_with(|current| {
+            let mut current = current.borrow_mut();
+            let duplicate = !current.ids.insert(id.clone());
+            current.stack.push((id, duplicate));
+        });
+    }
+
+    fn exit(&self, id: &Id) {
+        let popped = CONTEXT
+            .try_with(|current| {
+                let mut current = current.borrow_mut();
+                if current.stack.last().map(|(top, _)| top) != Some(id) {
+                    return None;
+                }
+                let (id, duplicate) = current.stack.pop()?;
+                if !duplicate {
+                    current.ids.remove(&id);
+                }
+                Some(id)
+            })
+            .ok()
+            .flatten();
+        if let Some(id) = popped {
+            self.try_close(id);
+        }
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.slots.read(id.into_u64() as usize - 1, |slot| {
+            if let Some(ref data) = slot.span {
+                data.ref_count.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let idx = id.into_u64() as usize - 1;
+        let last = self.slots.read(idx, |slot| match slot.span {
+            Some(ref data) => data.ref_count.fetch_sub(1, Ordering::Release) == 1,
+            None => false,
+        });
+        if last != Some(true) {
+            return false;
+        }
+        atomic::fence(Ordering::Acquire);
+        if let Some(parent) = self.slots.remove(idx).and_then(|data| data.parent) {
+            self.try_close(parent);
+        }
+        true
+    }
+}
+
+// ===== impl VecSlab =====
+
+impl Slots for VecSlab {
+    fn insert(&self, slot: Slot) -> usize {
+        let mut slot = Some(slot);
+        loop {
+            let head = self.next.load(Ordering::Relaxed);
+            {
+                let slab = self.slab.read().unwrap();
+                if head < slab.len() {
+                    if let Ok(mut free) = slab[head].try_write() {
+                        if free.slot.span.is_none()
+                            && self
+                                .next
+                                .compare_exchange(
+                                    head,
+                                    free.next,
+                                    Ordering::Release,
+                                    Ordering::Relaxed,
+                                )
+                                .is_ok()
+                        {
+                            free.slot = slot.take().unwrap();
+                            return head;
+                        }
+                    }
+                    hint::spin_loop();
+                    continue;
+                }
+            }
+
+            if let Ok(mut slab) = self.slab.try_write() {
+                let len = slab.len();
+                slab.push(RwLock::new(VecSlot {
+                    slot: slot.take().unwrap(),
+                    next: 0,
+                }));
+                self.next.store(len + 1, Ordering::Release);
+                return len;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    fn read<R>(&self, idx: usize, f: impl FnOnce(&Slot) -> R) -> Option<R> {
+        let slab = self.slab.read().unwrap();
+        let slot = slab.get(idx)?.read().unwrap();
+        Some(f(&slot.slot))
+    }
+
+    fn write<R>(&self, idx: usize, f: impl FnOnce(&mut Slot) -> R) -> Option<R> {
+        let slab = self.slab.read().unwrap();
+        let mut slot = slab.get(idx)?.write().unwrap();
+        Some(f(&mut slot.slot))
+    }
+
+    fn remove(&self, idx: usize) -> Option<Data> {
+        let slab = self.slab.read().unwrap();
+        loop {
+            let head = self.next.load(Ordering::Relaxed);
+            let mut slot = slab[idx].write().unwrap();
+            let data = slot.slot.span.take()?;
+            slot.next = head;
+            if self
+                .next
+                .compare_exchange(head, idx, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                slot.slot.fields.clear();
+                return Some(data);
+            }
+            slot.slot.span = Some(data);
+            hint::spin_loop();
+        }
+    }
+}
+
+// ===== impl Slab =====
+
+impl Slots for Slab<Slot> {
+    fn insert(&self, slot: Slot) -> usize {
+        let (idx, entry) = self.alloc().expect("span slab is full");
+        *entry.slot.write().unwrap() = slot;
+        idx
+    }
+
+    fn read<R>(&self, idx: usize, f: impl FnOnce(&Slot) -> R) -> Option<R> {
+        Some(f(&self.get(idx)?.slot.read().unwrap()))
+    }
+
+    fn write<R>(&self, idx: usize, f: impl FnOnce(&mut Slot) -> R) -> Option<R> {
+        Some(f(&mut self.get(idx)?.slot.write().unwrap()))
+    }
+
+    fn remove(&self, idx: usize) -> Option<Data> {
+        let mut slot = self.get(idx)?.slot.write().unwrap();
+        let data = slot.span.take()?;
+        slot.fields.clear();
+        drop(slot);
+        self.release(idx);
+        Some(data)
+    }
+}
+
+struct Recorder<'a>(&'a mut String);
+
+impl<'a> Visit for Recorder<'a> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = write!(self.0, "{}={:?} ", field.name(), value);
+    }
+}
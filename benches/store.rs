@@ -0,0 +1,99 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::{
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{dispatcher::Dispatch, span, Level};
+use tracing_concat::__bench::Slab;
+
+mod minimal;
+
+use minimal::{Minimal, VecSlab};
+
+const THREADS: &[usize] = &[1, 2, 4, 8, 16, 32, 64];
+
+/// Returns a fresh dispatcher for each slab being compared: the paged slab,
+/// and the slab it replaced. Both use the same minimal subscriber.
+fn dispatches() -> Vec<(&'static str, Dispatch)> {
+    vec![
+        ("paged", Dispatch::new(Minimal::<Slab<_>>::default())),
+        ("baseline", Dispatch::new(Minimal::<VecSlab>::default())),
+    ]
+}
+
+/// Runs `f` on `threads` threads at once, each with `dispatch` as its default
+/// dispatcher, and returns how long it took for all of them to finish.
+fn concurrent<F>(dispatch: &Dispatch, threads: usize, iters: u64, f: F) -> Duration
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let start = Arc::new(Barrier::new(threads + 1));
+    let end = Arc::new(Barrier::new(threads + 1));
+    let handles = (0..threads)
+        .map(|_| {
+            let (dispatch, f) = (dispatch.clone(), f.clone());
+            let (start, end) = (start.clone(), end.clone());
+            thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    start.wait();
+                    for _ in 0..iters {
+                        f();
+                    }
+                    end.wait();
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    start.wait();
+    let t0 = Instant::now();
+    end.wait();
+    let elapsed = t0.elapsed();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    elapsed
+}
+
+fn new_span(c: &mut Criterion) {
+    let mut group = c.benchmark_group("new_span");
+    for &threads in THREADS {
+        for (name, dispatch) in dispatches() {
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| {
+                    concurrent(&dispatch, threads, iters, || {
+                        let span = span!(Level::TRACE, "span", foo = 42);
+                        criterion::black_box(&span);
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn nested_spans(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nested_spans");
+    for &threads in THREADS {
+        for (name, dispatch) in dispatches() {
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| {
+                    concurrent(&dispatch, threads, iters, || {
+                        let parent = span!(Level::TRACE, "parent");
+                        let _enter = parent.enter();
+                        let child = span!(Level::TRACE, "child", bar = "baz");
+                        let _enter = child.enter();
+                        criterion::black_box(&child);
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, new_span, nested_spans);
+criterion_main!(benches);
@@ -0,0 +1,347 @@
+//! Capturing the record of a unit of work as a value, rather than writing it.
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::OnceLock,
+    task::{self, Poll},
+};
+use tracing::{
+    dispatcher,
+    field::{Field, Value},
+    span::Attributes,
+    Dispatch, Id,
+};
+
+use crate::{
+    record::ConcatRecord,
+    report,
+    store::{Captured, Store},
+    TracingConcat,
+};
+
+/// A future which runs the wrapped future inside a fresh root span, and
+/// completes with the wrapped future's output and that span's record.
+///
+/// This is returned by [`capture_async`].
+///
+/// [`capture_async`]: fn.capture_async.html
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CaptureFuture<F> {
+    inner: F,
+    capturing: Option<Capturing>,
+}
+
+#[derive(Debug)]
+struct Capturing {
+    dispatch: Dispatch,
+    // The capture span's ID in the store of the `TracingConcat` in
+    // `dispatch`. We hold one reference to it until we finish.
+    id: Id,
+    captured: Captured,
+}
+
+/// Exits the capture span when dropped, even if the closure panicked.
+struct Exit<'a> {
+    spans: &'a Store,
+    id: &'a Id,
+}
+
+/// Runs a closure inside a fresh root span, and returns its result along with
+/// the concatenated record of everything logged inside that span.
+///
+/// The record is returned instead of being written out. The span is created
+/// directly in the span store, so it is recorded whatever the subscriber's
+/// filters, and its record goes through the subscriber's redaction and
+/// limits as usual. If the current default subscriber is not a
+/// [`TracingConcat`], or a subscriber with a [`TracingConcatLayer`], a
+/// `TracingConcat` with the default configuration, shared by every such
+/// capture, is used for the duration of the closure.
+///
+/// If something else still holds a reference to the span when the closure
+/// returns (e.g. a spawned task), the record contains only what has been
+/// recorded so far and is marked as incomplete. The full record is written
+/// out as usual once the span closes.
+///
+/// [`TracingConcat`]: struct.TracingConcat.html
+/// [`TracingConcatLayer`]: struct.TracingConcatLayer.html
+pub fn capture<F, R>(f: F) -> (R, ConcatRecord)
+where
+    F: FnOnce() -> R,
+{
+    let capturing = Capturing::new();
+    let result = capturing.in_scope(f);
+    (result, capturing.finish())
+}
+
+/// Like [`capture`], but runs a future to completion inside the span.
+///
+/// [`capture`]: fn.capture.html
+pub fn capture_async<F: Future>(future: F) -> CaptureFuture<F> {
+    CaptureFuture {
+        inner: future,
+        capturing: Some(Capturing::new()),
+    }
+}
+
+// ===== impl CaptureFuture =====
+
+impl<F: Future> Future for CaptureFuture<F> {
+    type Output = (F::Output, ConcatRecord);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        // Safety: `inner` is structurally pinned and is never moved out of
+        // `self`. No other field is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+        let capturing = this
+            .capturing
+            .as_ref()
+            .expect("`CaptureFuture` polled after completion");
+
+        match capturing.in_scope(|| inner.poll(cx)) {
+            Poll::Ready(output) => {
+                let capturing = this.capturing.take().expect("checked above");
+                Poll::Ready((output, capturing.finish()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// ===== impl Capturing =====
+
+impl Capturing {
+    fn new() -> Self {
+        let mut dispatch = dispatcher::get_default(Dispatch::clone);
+        if crate::spans(&dispatch).is_none() {
+            dispatch = fallback();
+        }
+        let spans = crate::spans(&dispatch).expect("the fallback is a `TracingConcat`");
+
+        // The span is created in the store itself, rather than through the
+        // dispatcher, so that no filter can disable it.
+        let metadata = report::capture();
+        let values: [(&Field, Option<&dyn Value>); 0] = [];
+        let values = metadata.fields().value_set(&values);
+        let id = spans.new_span(&Attributes::new_root(metadata, &values), None);
+        let captured = Captured::default();
+        // This fails if the slab is full, in which case the span is disabled.
+        spans.capture(&id, captured.clone());
+        Self {
+            dispatch,
+            id,
+            captured,
+        }
+    }
+
+    fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        dispatcher::with_default(&self.dispatch, || {
+            let spans = self.spans();
+            spans.push(&self.id);
+            let _exit = Exit {
+                spans,
+                id: &self.id,
+            };
+            f()
+        })
+    }
+
+    fn finish(self) -> ConcatRecord {
+        let dispatch = self.dispatch.clone();
+        let id = self.id.clone();
+        let captured = self.captured.clone();
+        // If this was the last reference to the span, dropping it closes the
+        // span and puts its record in `captured`.
+        drop(self);
+
+        let spans = crate::spans(&dispatch).expect("checked in `new`");
+        let take = || captured.lock().unwrap_or_else(|e| e.into_inner()).take();
+        take()
+            .or_else(|| spans.snapshot(&id))
+            // The span may have closed after we checked, but before we took
+            // a snapshot.
+            .or_else(take)
+            .unwrap_or_else(report::capture_failed)
+    }
+
+    fn spans(&self) -> &Store {
+        crate::spans(&self.dispatch).expect("checked in `new`")
+    }
+}
+
+impl Drop for Capturing {
+    fn drop(&mut self) {
+        // Let go of the capture first: if nobody is waiting for the record,
+        // e.g. because the closure panicked or the future was dropped, the
+        // span's record is then flushed as usual when it closes.
+        drop(mem::take(&mut self.captured));
+        let _ = self.spans().drop_span(self.id.clone());
+    }
+}
+
+impl Drop for Exit<'_> {
+    fn drop(&mut self) {
+        self.spans.pop(self.id);
+    }
+}
+
+/// Returns the subscriber used to capture records when no `TracingConcat` is
+/// installed.
+///
+/// It is created once, rather than for each capture, since registering a new
+/// dispatcher rebuilds the interest of every callsite.
+fn fallback() -> Dispatch {
+    static FALLBACK: OnceLock<Dispatch> = OnceLock::new();
+    FALLBACK
+        .get_or_init(|| Dispatch::new(TracingConcat::default()))
+        .clone()
+}
+
+// These run under Miri, which checks the pin projection in `poll`.
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::{
+        panic,
+        sync::{Arc, Mutex},
+        task::Waker,
+    };
+
+    /// A future which is pending the first time it is polled.
+    struct Yield(bool);
+
+    impl Future for Yield {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut task::Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+
+    fn subscriber() -> TracingConcat {
+        TracingConcat::builder().on_flush(|_| {}).build()
+    }
+
+    /// Builds a subscriber from `builder` which collects the records it
+    /// flushes.
+    fn collecting(builder: crate::Builder) -> (TracingConcat, Arc<Mutex<Vec<ConcatRecord>>>) {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let flushed = records.clone();
+        let subscriber = builder
+            .on_flush(move |record| flushed.lock().unwrap().push(record.clone()))
+            .build();
+        (subscriber, records)
+    }
+
+    #[test]
+    fn capture_returns_record() {
+        let (result, record) = tracing::subscriber::with_default(subscriber(), || {
+            capture(|| {
+                tracing::info!(n = 1, "inside");
+                2
+            })
+        });
+        assert_eq!(result, 2);
+        assert!(!record.is_incomplete());
+        assert_eq!(record.root().name(), "capture");
+        assert_eq!(record.root().events().len(), 1);
+    }
+
+    #[test]
+    fn capture_ignores_level_filter() {
+        let subscriber = TracingConcat::builder()
+            .with_max_level(tracing::Level::WARN)
+            .on_flush(|_| panic!("captured records are not flushed"))
+            .build();
+        let (_, record) = tracing::subscriber::with_default(subscriber, || {
+            capture(|| {
+                tracing::info!("filtered");
+                tracing::warn!("kept");
+            })
+        });
+        assert!(!record.is_incomplete());
+        assert_eq!(record.root().name(), "capture");
+        assert_eq!(record.root().events().len(), 1);
+    }
+
+    #[test]
+    fn capture_async_polls_inside_span() {
+        let future = capture_async(async {
+            tracing::info!("before");
+            Yield(false).await;
+            tracing::info!("after");
+            3
+        });
+        let mut future = Box::pin(future);
+        let mut cx = task::Context::from_waker(Waker::noop());
+        let output = tracing::subscriber::with_default(subscriber(), || loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                break output;
+            }
+        });
+        let (result, record) = output;
+        assert_eq!(result, 3);
+        assert_eq!(record.root().events().len(), 2);
+    }
+
+    #[test]
+    fn panicking_capture_is_flushed() {
+        let (subscriber, records) = collecting(TracingConcat::builder());
+        let panicked = tracing::subscriber::with_default(subscriber, || {
+            panic::catch_unwind(|| {
+                capture(|| {
+                    tracing::info!("before the panic");
+                    panic!("oh no");
+                })
+            })
+        });
+        assert!(panicked.is_err());
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].root().name(), "capture");
+        assert_eq!(records[0].root().events().len(), 1);
+    }
+
+    #[test]
+    fn dropped_capture_future_is_flushed() {
+        let (subscriber, records) = collecting(TracingConcat::builder());
+        let mut cx = task::Context::from_waker(Waker::noop());
+        tracing::subscriber::with_default(subscriber, || {
+            let mut future = Box::pin(capture_async(async {
+                tracing::info!("before");
+                Yield(false).await;
+                tracing::info!("never recorded");
+            }));
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+            drop(future);
+        });
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].root().name(), "capture");
+        assert_eq!(records[0].root().events().len(), 1);
+    }
+
+    #[test]
+    fn capture_keeps_children_when_flushing_each_span() {
+        let builder = TracingConcat::builder().with_flush_mode(crate::FlushMode::EachSpan);
+        let (subscriber, records) = collecting(builder);
+        let (_, record) = tracing::subscriber::with_default(subscriber, || {
+            capture(|| {
+                tracing::info_span!("db").in_scope(|| tracing::info!("queried"));
+            })
+        });
+        assert!(records.lock().unwrap().is_empty());
+        let children = record.root().children();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].name(), "db");
+        assert_eq!(children[0].events().len(), 1);
+    }
+}
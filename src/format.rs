@@ -0,0 +1,363 @@
+//! Formats for writing out concatenated records.
+use std::{
+    collections::HashMap,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map};
+
+use crate::record::{BufferedEvent, ConcatRecord, Fields, SpanRecord, Value};
+
+/// Writes a [`ConcatRecord`] in some format.
+///
+/// [`ConcatRecord`]: ../struct.ConcatRecord.html
+pub trait FormatRecord: Send + Sync + 'static {
+    fn format_record(&self, record: &ConcatRecord, writer: &mut dyn io::Write) -> io::Result<()>;
+}
+
+/// Writes records as an indented tree of spans, with each span's events
+/// listed beneath it.
+///
+/// Each span's line includes its span ID. The root span's line also includes
+/// the trace ID, and the ID of the span's parent in another service, if any.
+///
+/// This is meant for humans, e.g. when writing to a terminal.
+#[derive(Clone, Debug, Default)]
+pub struct Tree;
+
+/// Writes each record as a single line of JSON (NDJSON).
+///
+/// Timestamps are written as seconds since the Unix epoch, and durations in
+/// milliseconds.
+#[derive(Clone, Debug, Default)]
+pub struct Json;
+
+/// Writes each record as a single "wide event": one line containing the
+/// fields of every span in the record.
+///
+/// The root span's fields are written as they are. The fields of other spans
+/// are prefixed with their path from the root: the names of the span and of
+/// its ancestors below the root, each followed by a separator, e.g.
+/// `db_query.table` for a child of the root, and `db_query.connect.host` for
+/// one of its children. Keys can therefore only collide between spans with
+/// the same path, such as several children of a span with the same name.
+/// Each span's ID and duration are included as `span_id` and `duration_ms`
+/// fields, and the record's own metadata, such as `record_id` and
+/// `trace_id`, as unprefixed fields. Events are not written.
+///
+/// These metadata keys are written first, and take precedence over span
+/// fields with the same keys: under [`Collision::KeepBoth`], such a span
+/// field is kept with a numeric suffix, and otherwise it is dropped.
+///
+/// By default, lines are written as `key=value` pairs (logfmt). Use
+/// [`Flat::json`] to write them as flat JSON objects instead.
+///
+/// [`Flat::json`]: struct.Flat.html#method.json
+/// [`Collision::KeepBoth`]: enum.Collision.html#variant.KeepBoth
+#[derive(Clone, Debug)]
+pub struct Flat {
+    separator: String,
+    collision: Collision,
+    json: bool,
+}
+
+/// What [`Flat`] does when several spans produce the same key, e.g. when a
+/// span has several children with the same name.
+///
+/// Spans are visited parent first, and children in the order they closed.
+///
+/// [`Flat`]: struct.Flat.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collision {
+    /// Keep the value from the span which was visited last, e.g. the last of
+    /// several children with the same name.
+    ChildWins,
+    /// Keep the value from the span which was visited first, e.g. the first
+    /// of several children with the same name.
+    ParentWins,
+    /// Keep every value, adding a numeric suffix to the keys of later values,
+    /// e.g. `db_query.table_2`.
+    KeepBoth,
+}
+
+// ===== impl Tree =====
+
+impl FormatRecord for Tree {
+    fn format_record(&self, record: &ConcatRecord, writer: &mut dyn io::Write) -> io::Result<()> {
+        write!(writer, "{}", record)
+    }
+}
+
+// ===== impl Json =====
+
+impl FormatRecord for Json {
+    fn format_record(&self, record: &ConcatRecord, writer: &mut dyn io::Write) -> io::Result<()> {
+        let mut json = span_to_json(record.root());
+        json["record_id"] = format!("{:016x}", record.id()).into();
+        json["trace_id"] = record.trace_id().to_string().into();
+        json["level"] = record.level().to_string().into();
+        json["warn_count"] = record.warn_count().into();
+        json["error_count"] = record.error_count().into();
+        if let Some(rate) = record.sample_rate() {
+            json["sample_rate"] = rate.into();
+        }
+        if let Some(parent) = record.parent_span_id() {
+            json["parent_span_id"] = parent.to_string().into();
+        }
+        let (part, parts) = record.part();
+        if parts > 1 {
+            json["part"] = part.into();
+            json["parts"] = parts.into();
+        }
+        if let Some(snapshot) = record.snapshot() {
+            json["snapshot"] = snapshot.into();
+        }
+        if record.is_incomplete() {
+            json["incomplete"] = true.into();
+        }
+        serde_json::to_writer(&mut *writer, &json)?;
+        writer.write_all(b"\n")
+    }
+}
+
+// ===== impl Flat =====
+
+impl Default for Flat {
+    fn default() -> Self {
+        Self {
+            separator: ".".to_owned(),
+            collision: Collision::ChildWins,
+            json: false,
+        }
+    }
+}
+
+impl Flat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the separator between span names and field names. Defaults to
+    /// `.`.
+    pub fn with_separator(self, separator: impl Into<String>) -> Self {
+        Self {
+            separator: separator.into(),
+            ..self
+        }
+    }
+
+    /// Sets what happens when several spans produce the same key. Defaults
+    /// to [`Collision::ChildWins`].
+    ///
+    /// [`Collision::ChildWins`]: enum.Collision.html#variant.ChildWins
+    pub fn with_collision(self, collision: Collision) -> Self {
+        Self { collision, ..self }
+    }
+
+    /// Writes each record as a flat JSON object, rather than as `key=value`
+    /// pairs.
+    pub fn json(self) -> Self {
+        Self { json: true, ..self }
+    }
+
+    fn flatten(&self, record: &ConcatRecord) -> Vec<(String, serde_json::Value)> {
+        let mut flat = Flattened {
+            fields: Vec::new(),
+            keys: HashMap::new(),
+            collision: self.collision,
+        };
+        let mut metadata = |key: &str, value: serde_json::Value| {
+            flat.insert(key.to_owned(), value, true);
+        };
+        let root = record.root();
+        metadata("name", root.name().into());
+        metadata("record_id", format!("{:016x}", record.id()).into());
+        metadata("trace_id", record.trace_id().to_string().into());
+        metadata("span_id", root.id().to_string().into());
+        if let Some(parent) = record.parent_span_id() {
+            metadata("parent_span_id", parent.to_string().into());
+        }
+        metadata("level", record.level().to_string().into());
+        metadata("warn_count", record.warn_count().into());
+        metadata("error_count", record.error_count().into());
+        if let Some(rate) = record.sample_rate() {
+            metadata("sample_rate", rate.into());
+        }
+        let (part, parts) = record.part();
+        if parts > 1 {
+            metadata("part", part.into());
+            metadata("parts", parts.into());
+        }
+        if let Some(snapshot) = record.snapshot() {
+            metadata("snapshot", snapshot.into());
+        }
+        if record.is_incomplete() {
+            metadata("incomplete", true.into());
+        }
+
+        // Every span's metadata goes in before any span's fields, so that
+        // the fields can't displace it.
+        self.flatten_span(root, None, &mut flat, true);
+        self.flatten_span(root, None, &mut flat, false);
+        flat.fields
+    }
+
+    /// Inserts either the metadata or the fields of `span` and its
+    /// descendants.
+    fn flatten_span(
+        &self,
+        span: &SpanRecord,
+        prefix: Option<&str>,
+        flat: &mut Flattened,
+        metadata: bool,
+    ) {
+        let key = |name: &str| match prefix {
+            Some(prefix) => format!("{}{}{}", prefix, self.separator, name),
+            None => name.to_owned(),
+        };
+        if metadata {
+            // The root span's ID was inserted with the record's metadata.
+            if prefix.is_some() {
+                flat.insert(key("span_id"), span.id().to_string().into(), true);
+            }
+            let duration = span.duration().as_secs_f64() * 1000.0;
+            flat.insert(key("duration_ms"), duration.into(), true);
+        } else {
+            for (name, value) in span.fields().iter() {
+                flat.insert(key(name), value_to_json(value), false);
+            }
+        }
+        for child in span.children() {
+            let path = match prefix {
+                Some(prefix) => format!("{}{}{}", prefix, self.separator, child.name()),
+                None => child.name().to_owned(),
+            };
+            self.flatten_span(child, Some(&path), flat, metadata);
+        }
+    }
+}
+
+impl FormatRecord for Flat {
+    fn format_record(&self, record: &ConcatRecord, writer: &mut dyn io::Write) -> io::Result<()> {
+        let fields = self.flatten(record);
+        if self.json {
+            let object = fields.into_iter().collect::<Map<_, _>>();
+            serde_json::to_writer(&mut *writer, &object)?;
+            return writer.write_all(b"\n");
+        }
+
+        let mut line = String::new();
+        for (key, value) in fields {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&key);
+            line.push('=');
+            match value {
+                serde_json::Value::String(string) => {
+                    let quote = string.is_empty()
+                        || string.contains(|c: char| c.is_whitespace() || c == '"' || c == '=');
+                    if quote {
+                        line.push_str(&serde_json::Value::String(string).to_string());
+                    } else {
+                        line.push_str(&string);
+                    }
+                }
+                value => line.push_str(&value.to_string()),
+            }
+        }
+        line.push('\n');
+        writer.write_all(line.as_bytes())
+    }
+}
+
+/// The fields of a [`Flat`] record, as they are collected.
+struct Flattened {
+    fields: Vec<(String, serde_json::Value)>,
+    // Maps keys to their index in `fields`, and whether they are metadata
+    // rather than span fields.
+    keys: HashMap<String, (usize, bool)>,
+    collision: Collision,
+}
+
+impl Flattened {
+    fn insert(&mut self, key: String, value: serde_json::Value, metadata: bool) {
+        let (idx, existing_metadata) = match self.keys.get(&key) {
+            Some(&existing) => existing,
+            None => {
+                self.keys.insert(key.clone(), (self.fields.len(), metadata));
+                self.fields.push((key, value));
+                return;
+            }
+        };
+        // Metadata is always inserted first, so a span field never displaces
+        // it.
+        let collision = match self.collision {
+            Collision::ChildWins if existing_metadata && !metadata => Collision::ParentWins,
+            collision => collision,
+        };
+        match collision {
+            Collision::ChildWins => self.fields[idx].1 = value,
+            Collision::ParentWins => {}
+            Collision::KeepBoth => {
+                let key = (2..)
+                    .map(|n| format!("{}_{}", key, n))
+                    .find(|key| !self.keys.contains_key(key))
+                    .expect("there is always an unused suffix");
+                self.keys.insert(key.clone(), (self.fields.len(), metadata));
+                self.fields.push((key, value));
+            }
+        }
+    }
+}
+
+fn span_to_json(span: &SpanRecord) -> serde_json::Value {
+    json!({
+        "name": span.name(),
+        "span_id": span.id().to_string(),
+        "target": span.metadata().target(),
+        "start": timestamp(span.start()),
+        "duration_ms": span.duration().as_secs_f64() * 1000.0,
+        "fields": fields_to_json(span.fields()),
+        "events": span.events().iter().map(event_to_json).collect::<Vec<_>>(),
+        "children": span.children().iter().map(span_to_json).collect::<Vec<_>>(),
+    })
+}
+
+fn event_to_json(event: &BufferedEvent) -> serde_json::Value {
+    let mut json = json!({
+        "timestamp": timestamp(event.timestamp()),
+        "level": event.metadata().level().to_string(),
+        "target": event.metadata().target(),
+        "fields": fields_to_json(event.fields()),
+    });
+    if event.repeated() > 1 {
+        json["repeated"] = event.repeated().into();
+        json["last_timestamp"] = timestamp(event.last_timestamp()).into();
+    }
+    json
+}
+
+fn fields_to_json(fields: &Fields) -> serde_json::Value {
+    let fields = fields
+        .iter()
+        .map(|(name, value)| (name.to_owned(), value_to_json(value)))
+        .collect::<Map<_, _>>();
+    fields.into()
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Bool(value) => (*value).into(),
+        Value::I64(value) => (*value).into(),
+        Value::U64(value) => (*value).into(),
+        Value::Str(value) | Value::Debug(value) => value.as_str().into(),
+    }
+}
+
+fn timestamp(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs_f64())
+        .unwrap_or(0.0)
+}
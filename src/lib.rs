@@ -1,98 +1,271 @@
 use chashmap::CHashMap;
-use std::io;
+use std::{
+    fmt,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 use tracing::{
-    span,
+    dispatcher, span,
     subscriber::{self, Subscriber},
-    Event, Id, Metadata,
+    Dispatch, Event, Id, Level, Metadata,
 };
 use tracing_core::span::Current;
-use tracing_subscriber::{
-    fmt::format::{DefaultFields, Format, Full},
-    layer::{Context, Layer},
-};
+use tracing_subscriber::layer::{Context, Layer};
 
+mod capture;
+pub mod format;
+mod limit;
+mod panic;
+mod record;
+pub mod redact;
+mod report;
+mod sink;
+mod stats;
 mod store;
-use store::Store;
+mod sync;
+mod trace;
+pub use capture::{capture, capture_async, CaptureFuture};
+use limit::RateLimits;
+pub use panic::PanicHook;
+use record::Recorder;
+pub use record::{BufferedEvent, ConcatRecord, Dedup, Fields, SpanRecord, Value};
+pub use redact::Redactor;
+use sink::Flusher;
+pub use sink::{ConcatSink, RoutingSink, WriterSink};
+pub use stats::Stats;
+use store::{Config, Store};
+pub use store::{FlushMode, Heartbeat, Span};
+pub use trace::{SpanId, TraceId};
+
+/// Not part of the public API: exposes the span slab to the benchmarks, so
+/// that it can be compared with the slab it replaced on its own.
+#[doc(hidden)]
+pub mod __bench {
+    pub use crate::store::slab::{Entry, Slab};
+}
+
+/// Returns the ID of the trace that the current span belongs to, e.g. to
+/// return it to clients in a response header.
+///
+/// Returns `None` if there is no current span, or if the current default
+/// subscriber is not a [`TracingConcat`] or a subscriber with a
+/// [`TracingConcatLayer`].
+///
+/// [`TracingConcat`]: struct.TracingConcat.html
+/// [`TracingConcatLayer`]: struct.TracingConcatLayer.html
+pub fn current_trace_id() -> Option<TraceId> {
+    dispatcher::get_default(|dispatch| store::Context::new(spans(dispatch)?).trace_id())
+}
+
+/// Returns the span store of `dispatch`, if it is a [`TracingConcat`] or a
+/// subscriber with a [`TracingConcatLayer`].
+fn spans(dispatch: &Dispatch) -> Option<&Store> {
+    match dispatch.downcast_ref::<TracingConcat>() {
+        Some(concat) => Some(&concat.spans),
+        None => Some(&dispatch.downcast_ref::<TracingConcatLayer>()?.inner.spans),
+    }
+}
 
+/// A [`Layer`] which concatenates the records of span trees, like
+/// [`TracingConcat`], for use alongside other layers.
+///
+/// The layer's level only applies to what it records itself. It never
+/// disables a span or event, so the other layers, such as a `fmt` layer,
+/// still see everything below it.
+///
+/// [`Layer`]: https://docs.rs/tracing-subscriber/0.2/tracing_subscriber/layer/trait.Layer.html
+/// [`TracingConcat`]: struct.TracingConcat.html
 pub struct TracingConcatLayer {
     inner: TracingConcat,
+    // Maps the IDs that the wrapped subscriber assigned to spans to the IDs
+    // of the same spans in our store.
+    ids: CHashMap<Id, Id>,
 }
 
 impl Default for TracingConcatLayer {
     fn default() -> Self {
-        Self {
-            inner: TracingConcat::default(),
-        }
+        Builder::default().build_layer()
     }
 }
 
-pub struct TracingConcat<N = DefaultFields, E = Format<Full>, W = fn() -> io::Stdout> {
-    fmt_fields: N,
-    fmt_event: E,
-    spans: Store,
-    events: CHashMap<Id, Vec<&'static Metadata<'static>>>,
-    make_writer: W,
+pub struct TracingConcat {
+    spans: Arc<Store>,
+    flusher: Arc<Flusher>,
 }
 
-impl<S: Subscriber> Layer<S> for TracingConcatLayer {
-    fn register_callsite(&self, meta: &'static Metadata<'static>) -> subscriber::Interest {
-        self.inner.register_callsite(meta)
+/// Configures a [`TracingConcat`] subscriber or [`TracingConcatLayer`].
+///
+/// [`TracingConcat`]: struct.TracingConcat.html
+/// [`TracingConcatLayer`]: struct.TracingConcatLayer.html
+#[derive(Default)]
+pub struct Builder {
+    sinks: Vec<Box<dyn ConcatSink>>,
+    redactor: Redactor,
+    max_field_len: Option<usize>,
+    max_record_bytes: Option<usize>,
+    dedup: Option<Dedup>,
+    flush_mode: FlushMode,
+    propagated: Vec<String>,
+    max_level: Option<Level>,
+    level_field: Option<String>,
+    sample_rate: Option<f64>,
+    span_sample_rates: Vec<(String, f64)>,
+    rate_limits: RateLimits,
+    heartbeats: Vec<(String, Heartbeat)>,
+    strict: bool,
+}
+
+/// A handle for inspecting the spans that are currently open from
+/// application code, e.g. to show what a request has logged so far.
+///
+/// A `Handle` can be obtained from a [`TracingConcat`] or
+/// [`TracingConcatLayer`] before it is installed, and remains usable after.
+///
+/// [`TracingConcat`]: struct.TracingConcat.html
+/// [`TracingConcatLayer`]: struct.TracingConcatLayer.html
+#[derive(Clone, Debug)]
+pub struct Handle {
+    spans: Arc<Store>,
+}
+
+/// Flushes everything recorded by spans which are still open when it is
+/// dropped, so that it isn't lost when the process exits.
+///
+/// Each open root span's record is flushed as it is so far, marked as
+/// incomplete, and then every sink is flushed. If those spans close after
+/// the guard is dropped, their full records are flushed as usual. Spans
+/// inside a [`capture`] are left to the capture.
+///
+/// Note that `std::process::exit` does not run destructors, so the guard
+/// must be dropped explicitly before calling it.
+///
+/// [`capture`]: fn.capture.html
+#[must_use = "everything recorded by open spans is flushed when the guard is dropped"]
+pub struct FlushGuard {
+    spans: Arc<Store>,
+    flusher: Arc<Flusher>,
+}
+
+impl TracingConcatLayer {
+    /// Returns a snapshot of this layer's runtime statistics.
+    pub fn stats(&self) -> Stats {
+        self.inner.stats()
     }
 
-    fn new_span(&self, attrs: &span::Attributes<'_>, _: &Id, _: Context<S>) {
-        self.inner.spans.new_span(attrs, &self.inner.fmt_fields);
+    /// Returns a handle for inspecting the spans tracked by this layer.
+    pub fn handle(&self) -> Handle {
+        self.inner.handle()
     }
 
-    fn on_record(&self, span: &Id, values: &span::Record<'_>, _: Context<S>) {
-        self.inner
-            .spans
-            .record(span, values, &self.inner.fmt_fields)
+    /// Returns a guard which flushes the spans that are still open when it
+    /// is dropped.
+    pub fn flush_guard(&self) -> FlushGuard {
+        self.inner.flush_guard()
+    }
+
+    #[inline]
+    fn id(&self, id: &Id) -> Option<Id> {
+        self.ids.get(id).map(|id| id.clone())
+    }
+}
+
+impl<S: Subscriber> Layer<S> for TracingConcatLayer {
+    // The layer doesn't implement `enabled` or `register_callsite`, since
+    // either would filter out spans and events for every other layer as
+    // well. Whatever is below the level is filtered out as it is recorded
+    // instead.
+
+    fn new_span(&self, attrs: &span::Attributes<'_>, id: &Id, _: Context<S>) {
+        if !self.inner.enabled(attrs.metadata()) {
+            return;
+        }
+        let parent = attrs.parent().and_then(|parent| self.id(parent));
+        let span = self.inner.spans.new_span(attrs, parent.as_ref());
+        self.ids.insert(id.clone(), span);
     }
 
-    fn on_event(&self, event: &Event<'_>, ctx: Context<S>) {
-        println!("ctx: {:?}", ctx.current_span());
-        println!("Event: {:?}", event);
+    fn on_record(&self, span: &Id, values: &span::Record<'_>, _: Context<S>) {
+        if let Some(span) = self.id(span) {
+            self.inner.spans.record(&span, values)
+        }
     }
 
-    fn enabled(&self, metadata: &Metadata, _: Context<S>) -> bool {
-        self.inner.enabled(metadata)
+    fn on_event(&self, event: &Event<'_>, _: Context<S>) {
+        if !self.inner.enabled(event.metadata()) {
+            return;
+        }
+        let parent = event.parent().and_then(|parent| self.id(parent));
+        self.inner.spans.push_event(event, parent.as_ref());
     }
 
     fn on_enter(&self, id: &Id, _: Context<S>) {
-        self.inner.enter(id);
+        if let Some(id) = self.id(id) {
+            self.inner.enter(&id);
+        }
     }
 
     fn on_exit(&self, id: &Id, _: Context<S>) {
-        self.inner.exit(id);
+        if let Some(id) = self.id(id) {
+            self.inner.exit(&id);
+        }
     }
 
-    fn on_close(&self, id: Id, ctx: Context<S>) {
-        if let Some(span) = self.inner.spans.get(&id) {
-            println!("Span: {:?}", span);
-            println!("Events: {:?}", self.inner.events)
+    fn on_close(&self, id: Id, _: Context<S>) {
+        if let Some(id) = self.ids.remove(&id) {
+            self.inner.spans.drop_span(id);
         }
     }
 }
 
 impl Default for TracingConcat {
     fn default() -> Self {
-        Self {
-            fmt_fields: DefaultFields::default(),
-            fmt_event: Format::default(),
-            make_writer: io::stdout,
-            spans: Store::with_capacity(32),
-            events: CHashMap::new(),
+        Builder::default().build()
+    }
+}
+
+impl TracingConcat {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Returns a snapshot of this subscriber's runtime statistics.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.spans.stats();
+        stats.records_flushed = self.flusher.flushed.load(Ordering::Relaxed);
+        stats
+    }
+
+    /// Returns a handle for inspecting the spans tracked by this subscriber.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            spans: self.spans.clone(),
+        }
+    }
+
+    /// Returns a guard which flushes the spans that are still open when it
+    /// is dropped.
+    pub fn flush_guard(&self) -> FlushGuard {
+        FlushGuard {
+            spans: self.spans.clone(),
+            flusher: self.flusher.clone(),
         }
     }
 }
 
 impl Subscriber for TracingConcat {
-    fn register_callsite(&self, _meta: &Metadata<'_>) -> subscriber::Interest {
-        subscriber::Interest::always()
+    fn register_callsite(&self, meta: &Metadata<'_>) -> subscriber::Interest {
+        // Callsites below the configured level may still be enabled inside
+        // span trees whose root overrides it, so that has to be checked each
+        // time.
+        if self.spans.config().enabled(meta, None) {
+            subscriber::Interest::always()
+        } else {
+            subscriber::Interest::sometimes()
+        }
     }
 
     fn new_span(&self, attrs: &span::Attributes<'_>) -> Id {
-        self.spans.new_span(attrs, &self.fmt_fields)
+        self.spans.new_span(attrs, attrs.parent())
     }
 
     fn record_follows_from(&self, _span: &Id, _follows: &Id) {
@@ -100,13 +273,15 @@ impl Subscriber for TracingConcat {
     }
 
     fn record(&self, span: &Id, values: &span::Record<'_>) {
-        self.spans.record(span, values, &self.fmt_fields)
+        self.spans.record(span, values)
     }
 
-    fn event(&self, _: &Event<'_>) {}
+    fn event(&self, event: &Event<'_>) {
+        self.spans.push_event(event, event.parent())
+    }
 
-    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.spans.enabled(metadata)
     }
 
     fn enter(&self, id: &Id) {
@@ -129,10 +304,383 @@ impl Subscriber for TracingConcat {
 
     fn current_span(&self) -> Current {
         if let Some(id) = self.spans.current() {
-            if let Some(meta) = self.spans.get(&id).map(|span| span.metadata()) {
+            if let Some(meta) = self.spans.metadata(&id) {
                 return Current::new(id, meta);
             }
         }
         Current::none()
     }
 }
+
+// ===== impl Builder =====
+
+impl Builder {
+    /// Adds a sink which receives the record of each root span when it
+    /// closes.
+    ///
+    /// Records are passed to every sink, in the order they were added. If no
+    /// sinks are added, records are written to stdout as a [`Tree`].
+    ///
+    /// [`Tree`]: format/struct.Tree.html
+    pub fn with_sink(mut self, sink: impl ConcatSink) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Adds a callback which is called with the record of each root span when
+    /// it closes.
+    pub fn on_flush<F>(self, f: F) -> Self
+    where
+        F: Fn(&ConcatRecord) + Send + Sync + 'static,
+    {
+        self.with_sink(f)
+    }
+
+    /// Redacts sensitive data from span and event fields as they are
+    /// recorded.
+    pub fn with_redactor(self, redactor: Redactor) -> Self {
+        Self { redactor, ..self }
+    }
+
+    /// Truncates string field values longer than `len` bytes as they are
+    /// recorded.
+    pub fn with_max_field_len(self, len: usize) -> Self {
+        Self {
+            max_field_len: Some(len),
+            ..self
+        }
+    }
+
+    /// Splits records larger than `bytes` into several numbered parts,
+    /// which share a record ID. Every part repeats the root span's fields.
+    ///
+    /// Each part is measured as the largest number of bytes any sink would
+    /// write for it, so no part exceeds `bytes` in any sink's format, unless
+    /// it holds a single span's fields or event which is larger on its own.
+    /// Sinks which can't measure records, such as closures, fall back to an
+    /// estimate based on the records' fields.
+    pub fn with_max_record_bytes(self, bytes: usize) -> Self {
+        Self {
+            max_record_bytes: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Collapses repeated events inside each span into a single event,
+    /// which records how many times it occurred.
+    pub fn with_dedup(self, dedup: Dedup) -> Self {
+        Self {
+            dedup: Some(dedup),
+            ..self
+        }
+    }
+
+    /// Sets when records are flushed. By default, one record is flushed for
+    /// each root span, containing all of its descendants.
+    pub fn with_flush_mode(self, mode: FlushMode) -> Self {
+        Self {
+            flush_mode: mode,
+            ..self
+        }
+    }
+
+    /// Copies the values of the fields with the given names from each span
+    /// into all of its descendant spans and events, unless they have their
+    /// own values for them.
+    ///
+    /// Values are copied into child spans when they are created, so a value
+    /// recorded on a span later is only inherited by children created after
+    /// that.
+    pub fn with_propagated_fields<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.propagated.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Only records spans and events at `level` or more severe, except in
+    /// span trees whose root span sets its own level.
+    ///
+    /// Root spans are recorded whatever their level, so that they can set
+    /// their own level, but a root span less severe than the level it ends
+    /// up with is only written if something inside it was recorded.
+    ///
+    /// By default, everything is recorded.
+    pub fn with_max_level(self, level: Level) -> Self {
+        Self {
+            max_level: Some(level),
+            ..self
+        }
+    }
+
+    /// Sets the name of the root span field which sets the level for that
+    /// root's span tree, overriding [`with_max_level`]. Defaults to
+    /// `log.level`.
+    ///
+    /// The field's value is a level name, such as `"trace"`. It may be
+    /// recorded when the root span is created, or later with `Span::record`,
+    /// e.g. once a request is known to need extra detail.
+    ///
+    /// Changing the level only affects spans and events created after the
+    /// change.
+    ///
+    /// [`with_max_level`]: #method.with_max_level
+    pub fn with_level_field(self, name: impl Into<String>) -> Self {
+        Self {
+            level_field: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Keeps only a fraction `rate` of root spans, between 0 and 1, and
+    /// everything inside them.
+    ///
+    /// Whether to keep a root span is decided when it is created, from its
+    /// trace ID, so services which sample traces at the same rate keep the
+    /// same traces. Nothing is recorded inside root spans which are not
+    /// kept. Kept records have a [`sample_rate`].
+    ///
+    /// If a root span's `traceparent` or `trace_id` field is only recorded
+    /// after the span was created, the span keeps the decision made from the
+    /// trace ID it had before, since things may already have been dropped
+    /// or recorded because of it. Its record does get the new trace ID.
+    ///
+    /// [`sample_rate`]: struct.ConcatRecord.html#method.sample_rate
+    pub fn with_sample_rate(self, rate: f64) -> Self {
+        Self {
+            sample_rate: Some(rate.clamp(0.0, 1.0)),
+            ..self
+        }
+    }
+
+    /// Samples root spans with the given name at `rate`, rather than the
+    /// rate set by [`with_sample_rate`], e.g. to keep only 1% of `request`
+    /// spans.
+    ///
+    /// [`with_sample_rate`]: #method.with_sample_rate
+    pub fn with_span_sample_rate(mut self, name: impl Into<String>, rate: f64) -> Self {
+        self.span_sample_rates
+            .push((name.into(), rate.clamp(0.0, 1.0)));
+        self
+    }
+
+    /// Flushes at most `records` records of root spans with the given name
+    /// in any period of length `per`, e.g. to keep health checks from
+    /// flooding the output.
+    ///
+    /// Only the records of root spans which have closed count towards the
+    /// limit: heartbeat records, incomplete records flushed on exit, and the
+    /// records of child spans flushed with [`FlushMode::EachSpan`] are always
+    /// flushed, and captured records are never limited.
+    ///
+    /// Records over the limit are dropped. Once a period in which records
+    /// were dropped has passed, a `rate_limited` record reporting how many
+    /// were is flushed along with the next record, or by
+    /// [`Handle::flush_due`].
+    ///
+    /// [`FlushMode::EachSpan`]: enum.FlushMode.html#variant.EachSpan
+    /// [`Handle::flush_due`]: struct.Handle.html#method.flush_due
+    pub fn with_rate_limit(mut self, name: impl Into<String>, records: u32, per: Duration) -> Self {
+        self.rate_limits.add(name.into(), records, per);
+        self
+    }
+
+    /// Flushes heartbeat records for root spans with the given name while
+    /// they are open, so that long-lived spans are written out a piece at a
+    /// time rather than all at once when they close.
+    pub fn with_heartbeat(mut self, name: impl Into<String>, heartbeat: Heartbeat) -> Self {
+        self.heartbeats.push((name.into(), heartbeat));
+        self
+    }
+
+    /// Flushes a `span_misuse` warning record each time a span is misused,
+    /// naming the spans involved and where they were created.
+    ///
+    /// Misuses include exiting a span which is not the current span, e.g.
+    /// because an `Entered` guard was held across an `.await`, and closing
+    /// or cloning a span which has already closed. They are counted in
+    /// [`Stats`] whether or not this is enabled. A span which closed long
+    /// before it was misused may not be named, if other spans have since
+    /// reused its storage and closed in turn.
+    ///
+    /// [`Stats`]: struct.Stats.html
+    pub fn with_strict_diagnostics(self) -> Self {
+        Self {
+            strict: true,
+            ..self
+        }
+    }
+
+    pub fn build(mut self) -> TracingConcat {
+        if self.sinks.is_empty() {
+            self.sinks.push(Box::new(WriterSink::default()));
+        }
+        let flusher = Arc::new(Flusher {
+            sinks: self.sinks,
+            max_record_bytes: self.max_record_bytes,
+            flushed: Default::default(),
+        });
+        let flush = flusher.clone();
+        TracingConcat {
+            flusher,
+            spans: Arc::new(Store::new(
+                Box::new(move |record| flush.flush(record)),
+                Config {
+                    recorder: Recorder::new(self.redactor, self.max_field_len, self.dedup),
+                    flush_mode: self.flush_mode,
+                    propagated: self.propagated,
+                    max_level: self.max_level,
+                    level_field: self.level_field.unwrap_or_else(|| "log.level".to_owned()),
+                    sample_rate: self.sample_rate,
+                    span_sample_rates: self.span_sample_rates,
+                    heartbeats: self.heartbeats,
+                    rate_limits: self.rate_limits,
+                    strict: self.strict,
+                },
+            )),
+        }
+    }
+
+    pub fn build_layer(self) -> TracingConcatLayer {
+        TracingConcatLayer {
+            inner: self.build(),
+            ids: CHashMap::new(),
+        }
+    }
+
+    /// Builds a [`TracingConcat`] and sets it as the global default
+    /// subscriber, returning a guard which flushes the spans that are still
+    /// open when it is dropped.
+    ///
+    /// [`TracingConcat`]: struct.TracingConcat.html
+    pub fn try_init(self) -> Result<FlushGuard, dispatcher::SetGlobalDefaultError> {
+        let subscriber = self.build();
+        let guard = subscriber.flush_guard();
+        subscriber::set_global_default(subscriber)?;
+        Ok(guard)
+    }
+
+    /// Like [`try_init`], but panics if a global default subscriber was
+    /// already set.
+    ///
+    /// [`try_init`]: #method.try_init
+    pub fn init(self) -> FlushGuard {
+        self.try_init()
+            .expect("failed to set the global default subscriber")
+    }
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("sinks", &self.sinks.len())
+            .field("redactor", &self.redactor)
+            .field("max_field_len", &self.max_field_len)
+            .field("max_record_bytes", &self.max_record_bytes)
+            .field("dedup", &self.dedup)
+            .field("flush_mode", &self.flush_mode)
+            .field("propagated", &self.propagated)
+            .field("max_level", &self.max_level)
+            .field("level_field", &self.level_field)
+            .field("sample_rate", &self.sample_rate)
+            .field("span_sample_rates", &self.span_sample_rates)
+            .field("rate_limits", &self.rate_limits)
+            .field("heartbeats", &self.heartbeats)
+            .field("strict", &self.strict)
+            .finish()
+    }
+}
+
+// ===== impl FlushGuard =====
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        self.spans.flush_open();
+        self.spans.flush_summaries(true);
+        self.flusher.finish();
+    }
+}
+
+impl fmt::Debug for FlushGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlushGuard")
+            .field("spans", &self.spans)
+            .finish()
+    }
+}
+
+// ===== impl Handle =====
+
+impl Handle {
+    /// Applies a function to each span in the current thread's span context.
+    ///
+    /// The function is applied in order, beginning with the root of the trace,
+    /// and ending with the current span. If the function returns an error,
+    /// this will short-circuit.
+    ///
+    /// Each span is passed as a copy of what it has recorded so far,
+    /// including the records of its children which have already closed. No
+    /// lock is held while the function runs, so it may log, record fields,
+    /// or enter and exit spans.
+    ///
+    /// The IDs passed to the function are those of this crate's span store.
+    /// When used as a [`TracingConcatLayer`], they differ from the IDs assigned
+    /// by the wrapped subscriber.
+    ///
+    /// [`TracingConcatLayer`]: struct.TracingConcatLayer.html
+    pub fn visit_spans<F, E>(&self, f: F) -> Result<(), E>
+    where
+        F: FnMut(&Id, Span) -> Result<(), E>,
+    {
+        store::Context::new(&self.spans).visit_spans(f)
+    }
+
+    /// Executes a closure with a copy of the current span, if there is one.
+    pub fn with_current<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce((&Id, Span)) -> R,
+    {
+        store::Context::new(&self.spans).with_current(f)
+    }
+
+    /// Returns the ID of the trace that the current span belongs to.
+    pub fn current_trace_id(&self) -> Option<TraceId> {
+        store::Context::new(&self.spans).trace_id()
+    }
+
+    /// Flushes a `leaked_spans` warning record listing every span which has
+    /// been open for longer than `older_than`, and returns how many there
+    /// are.
+    ///
+    /// Spans which stay open much longer than expected have usually been
+    /// leaked, e.g. by a forgotten clone of a `tracing::Span`, and each one
+    /// takes up memory until it closes. Each span is listed with its fields,
+    /// the names of its ancestors, and where it was created.
+    ///
+    /// This can be called periodically, e.g. from a background task.
+    pub fn report_leaks(&self, older_than: Duration) -> usize {
+        self.spans.report_leaks(older_than)
+    }
+
+    /// Flushes the records which are due, without waiting for anything else
+    /// to be recorded or flushed:
+    ///
+    /// - a `rate_limited` record for each rate limit whose period has
+    ///   elapsed since its last summary, if it suppressed any records, and
+    /// - a heartbeat record for each open root span whose [heartbeat]
+    ///   interval has elapsed, even if it has recorded nothing since the
+    ///   last one.
+    ///
+    /// Otherwise, summaries are only flushed just before the next record of
+    /// any root span, or when a [`FlushGuard`] is dropped, and heartbeats
+    /// when something is recorded in their span tree. This can be called
+    /// periodically, e.g. from a background task.
+    ///
+    /// [heartbeat]: struct.Heartbeat.html
+    /// [`FlushGuard`]: struct.FlushGuard.html
+    pub fn flush_due(&self) {
+        self.spans.flush_due();
+    }
+}
@@ -0,0 +1,113 @@
+//! Rate limits on the records flushed for root spans with particular names.
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{record::ConcatRecord, report};
+
+/// A token bucket rate limit for each root span name which has one.
+#[derive(Default)]
+pub(crate) struct RateLimits {
+    limits: Vec<Limit>,
+}
+
+struct Limit {
+    name: String,
+    records: u32,
+    per: Duration,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    // The number of records suppressed since the last summary, and when that
+    // was.
+    suppressed: u64,
+    since: SystemTime,
+    summarized: Instant,
+}
+
+// ===== impl RateLimits =====
+
+impl RateLimits {
+    /// Limits the records of root spans named `name` to `records` per `per`.
+    pub(crate) fn add(&mut self, name: String, records: u32, per: Duration) {
+        let bucket = Bucket {
+            tokens: f64::from(records),
+            refilled: Instant::now(),
+            suppressed: 0,
+            since: SystemTime::now(),
+            summarized: Instant::now(),
+        };
+        self.limits.retain(|limit| limit.name != name);
+        self.limits.push(Limit {
+            name,
+            records,
+            per,
+            bucket: Mutex::new(bucket),
+        });
+    }
+
+    /// Returns `true` if a record of a root span named `name` may be flushed,
+    /// taking a token from its bucket if it has a rate limit.
+    pub(crate) fn allow(&self, name: &str) -> bool {
+        let limit = match self.limits.iter().find(|limit| limit.name == name) {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let mut bucket = limit.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let capacity = f64::from(limit.records);
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() / limit.per.as_secs_f64();
+        bucket.tokens = (bucket.tokens + refill * capacity).min(capacity);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            bucket.suppressed += 1;
+            false
+        }
+    }
+
+    /// Returns a record summarizing the records suppressed by each rate limit
+    /// whose period has elapsed since its last summary, if it suppressed any.
+    ///
+    /// If `all` is `true`, summaries are returned whether or not their period
+    /// has elapsed, e.g. because no more records will be flushed.
+    pub(crate) fn summaries(&self, all: bool) -> Vec<ConcatRecord> {
+        let mut summaries = Vec::new();
+        for limit in &self.limits {
+            let mut bucket = limit.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            if !all && bucket.summarized.elapsed() < limit.per {
+                continue;
+            }
+            if bucket.suppressed > 0 {
+                summaries.push(report::rate_limited(
+                    &limit.name,
+                    bucket.suppressed,
+                    bucket.since,
+                ));
+            }
+            bucket.suppressed = 0;
+            bucket.since = SystemTime::now();
+            bucket.summarized = Instant::now();
+        }
+        summaries
+    }
+}
+
+impl fmt::Debug for RateLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.limits
+                    .iter()
+                    .map(|limit| (&limit.name, limit.records, limit.per)),
+            )
+            .finish()
+    }
+}
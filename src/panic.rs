@@ -0,0 +1,86 @@
+//! Recording panics in the span they occur in.
+use std::{backtrace::Backtrace, panic};
+
+use tracing::{dispatcher, Dispatch};
+
+use crate::record::{Fields, Value};
+
+/// A panic hook which records panics in the span tree they occur in, so that
+/// its record says what went wrong.
+///
+/// When a thread panics inside a span, the root of its span tree is given an
+/// `outcome` field of `"panic"`, along with `panic.message` and
+/// `panic.location` fields, and optionally a `panic.backtrace`. An
+/// error-level `panic` event is added to the current span. As the panic
+/// unwinds, the spans it leaves close and their record is flushed as usual.
+///
+/// If panics abort the process instead, the records of every open span are
+/// flushed straight away, marked as incomplete.
+///
+/// The hook applies to panics on any thread whose default subscriber is a
+/// [`TracingConcat`] or a subscriber with a [`TracingConcatLayer`]. It calls
+/// the previously installed hook afterwards, so panics are still printed.
+///
+/// [`TracingConcat`]: struct.TracingConcat.html
+/// [`TracingConcatLayer`]: struct.TracingConcatLayer.html
+#[derive(Clone, Debug, Default)]
+pub struct PanicHook {
+    backtrace: bool,
+}
+
+impl PanicHook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Captures a backtrace of each panic, and records it in a
+    /// `panic.backtrace` field.
+    pub fn with_backtrace(self) -> Self {
+        Self { backtrace: true }
+    }
+
+    /// Installs the hook, in front of the current panic hook.
+    pub fn install(self) {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let message = match info.payload().downcast_ref::<&'static str>() {
+                Some(message) => (*message).to_owned(),
+                None => match info.payload().downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "Box<dyn Any>".to_owned(),
+                },
+            };
+            let location = info.location().map(|location| location.to_string());
+            self.record(message, location);
+            previous(info);
+        }));
+    }
+
+    fn record(&self, message: String, location: Option<String>) {
+        let mut event = Fields::new();
+        event.insert("message", Value::Debug(message.clone()));
+        let mut fields = Fields::new();
+        fields.insert("outcome", Value::Str("panic".to_owned()));
+        fields.insert("panic.message", Value::Str(message));
+        if let Some(location) = location {
+            event.insert("location", Value::Str(location.clone()));
+            fields.insert("panic.location", Value::Str(location));
+        }
+
+        let dispatch = dispatcher::get_default(Dispatch::clone);
+        let spans = match crate::spans(&dispatch) {
+            Some(spans) => spans,
+            None => return,
+        };
+        if self.backtrace {
+            let backtrace = Backtrace::force_capture().to_string();
+            fields.insert("panic.backtrace", Value::Str(backtrace));
+        }
+        let recorded = spans.record_panic(fields, event);
+        // Nothing will unwind to close the spans, so this is the last chance
+        // to flush them.
+        if recorded && cfg!(panic = "abort") {
+            spans.flush_open();
+        }
+    }
+}
@@ -0,0 +1,896 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
+    ops::Range,
+    time::{Duration, SystemTime},
+};
+use tracing_core::{
+    field::{Field, Visit},
+    Event, Level, Metadata,
+};
+
+use crate::{
+    redact::Redactor,
+    trace::{SpanId, Trace, TraceId},
+};
+
+/// The value of a single field recorded on a span or event.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Value {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    Str(String),
+    /// A value that was recorded using its `fmt::Debug` implementation.
+    Debug(String),
+}
+
+/// The fields recorded on a span or event, in the order they were first
+/// recorded.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Fields {
+    fields: Vec<(&'static str, Value)>,
+}
+
+/// Records the fields of spans and events, processing them before they are
+/// buffered.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    redactor: Option<Redactor>,
+    max_field_len: Option<usize>,
+    pub(crate) dedup: Option<Dedup>,
+}
+
+/// Which events inside a span are considered repeats of each other, and
+/// collapsed into a single event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dedup {
+    /// Events from the same callsite with the same field values.
+    CallsiteAndFields,
+    /// Events from the same callsite, whatever their field values. The
+    /// collapsed event keeps the field values of the first occurrence.
+    Callsite,
+}
+
+/// An event that was recorded inside a span and is buffered until the span's
+/// record is written.
+#[derive(Clone, Debug)]
+pub struct BufferedEvent {
+    metadata: &'static Metadata<'static>,
+    fields: Fields,
+    timestamp: SystemTime,
+    // If repeats of this event were collapsed into it, how many times it
+    // occurred, and when it last did.
+    repeated: usize,
+    last_timestamp: SystemTime,
+}
+
+/// Everything recorded inside a span: its fields, the events recorded inside
+/// it, and the records of its children.
+#[derive(Clone, Debug)]
+pub struct SpanRecord {
+    id: SpanId,
+    metadata: &'static Metadata<'static>,
+    fields: Fields,
+    events: Vec<BufferedEvent>,
+    children: Vec<SpanRecord>,
+    start: SystemTime,
+    duration: Duration,
+}
+
+/// The concatenated record of a root span and all of its descendants, which
+/// is written out when the root span closes.
+#[derive(Clone, Debug)]
+pub struct ConcatRecord {
+    id: u64,
+    trace: Trace,
+    root: SpanRecord,
+    incomplete: bool,
+    // The 1-based number of this part, and the total number of parts, if
+    // the record was split because it was too large.
+    part: usize,
+    parts: usize,
+    // The 1-based sequence number of this record among the records of its
+    // root span, if heartbeat records were flushed for it.
+    snapshot: Option<u64>,
+    // For the parts of a split record, the level and event counts of the
+    // whole record, since each part only holds some of its events.
+    whole: Option<Summary>,
+}
+
+/// The level and event counts of a record.
+#[derive(Clone, Copy, Debug)]
+struct Summary {
+    max_level: Option<Level>,
+    // The number of events at each level, indexed by severity.
+    event_counts: [usize; 5],
+}
+
+// ===== impl Value =====
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(value) => fmt::Display::fmt(value, f),
+            Value::I64(value) => fmt::Display::fmt(value, f),
+            Value::U64(value) => fmt::Display::fmt(value, f),
+            Value::Str(value) => fmt::Debug::fmt(value, f),
+            Value::Debug(value) => f.write_str(value),
+        }
+    }
+}
+
+// ===== impl Fields =====
+
+impl Fields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value of the field with the given name, if it was recorded.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns an iterator over the names and values of the recorded fields.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Value)> + '_ {
+        self.fields.iter().map(|(name, value)| (*name, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Sets the value of the field with the given name, replacing any value
+    /// previously recorded for it.
+    pub(crate) fn insert(&mut self, name: &'static str, value: Value) {
+        match self.fields.iter_mut().find(|(field, _)| *field == name) {
+            Some((_, existing)) => *existing = value,
+            None => self.fields.push((name, value)),
+        }
+    }
+
+    /// Merges `other` into these fields, with values in `other` taking
+    /// precedence.
+    pub(crate) fn extend(&mut self, other: Fields) {
+        for (name, value) in other.fields {
+            self.insert(name, value);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.fields.clear();
+    }
+
+    /// Inserts the fields from `other` which are not already present.
+    pub(crate) fn inherit(&mut self, other: &Fields) {
+        for (name, value) in other.iter() {
+            if self.get(name).is_none() {
+                self.fields.push((name, value.clone()));
+            }
+        }
+    }
+
+    /// Retains only the fields for which `f` returns `true`, passing it a
+    /// mutable reference to each value.
+    pub(crate) fn retain_mut(&mut self, mut f: impl FnMut(&'static str, &mut Value) -> bool) {
+        self.fields.retain_mut(|(name, value)| f(name, value))
+    }
+}
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field.name(), Value::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field.name(), Value::U64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field.name(), Value::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field.name(), Value::Str(value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field.name(), Value::Debug(format!("{:?}", value)));
+    }
+}
+
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (name, value) in self.iter() {
+            if !first {
+                f.write_str(" ")?;
+            }
+            first = false;
+            // Like `tracing_subscriber`'s default formatter, write an event's
+            // message without its field name.
+            if name == "message" {
+                write!(f, "{}", value)?;
+            } else {
+                write!(f, "{}={}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ===== impl Recorder =====
+
+impl Recorder {
+    pub(crate) fn new(
+        mut redactor: Redactor,
+        max_field_len: Option<usize>,
+        dedup: Option<Dedup>,
+    ) -> Self {
+        let redactor = if redactor.is_empty() {
+            None
+        } else {
+            redactor.init();
+            Some(redactor)
+        };
+        Self {
+            redactor,
+            max_field_len,
+            dedup,
+        }
+    }
+
+    /// Records fields using `record`, e.g. a span's `Attributes::record`.
+    pub(crate) fn fields(&self, record: impl FnOnce(&mut Fields)) -> Fields {
+        let mut fields = Fields::new();
+        record(&mut fields);
+        if let Some(ref redactor) = self.redactor {
+            redactor.redact(&mut fields);
+        }
+        if let Some(max) = self.max_field_len {
+            fields.retain_mut(|_, value| {
+                truncate(value, max);
+                true
+            });
+        }
+        fields
+    }
+
+    pub(crate) fn event(&self, event: &Event<'_>) -> BufferedEvent {
+        let fields = self.fields(|fields| event.record(fields));
+        BufferedEvent::new(event.metadata(), fields)
+    }
+}
+
+// ===== impl BufferedEvent =====
+
+impl BufferedEvent {
+    pub(crate) fn new(metadata: &'static Metadata<'static>, fields: Fields) -> Self {
+        let timestamp = SystemTime::now();
+        Self {
+            metadata,
+            fields,
+            timestamp,
+            repeated: 1,
+            last_timestamp: timestamp,
+        }
+    }
+
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata
+    }
+
+    pub fn fields(&self) -> &Fields {
+        &self.fields
+    }
+
+    /// Returns the time at which the event was recorded.
+    ///
+    /// If repeats of the event were collapsed into it, this is when it first
+    /// occurred.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Returns how many times the event occurred, if repeats of it were
+    /// collapsed into it, or 1.
+    pub fn repeated(&self) -> usize {
+        self.repeated
+    }
+
+    /// Returns when the event last occurred, if repeats of it were collapsed
+    /// into it. Otherwise, this is the same as `timestamp`.
+    pub fn last_timestamp(&self) -> SystemTime {
+        self.last_timestamp
+    }
+
+    /// Adds the values of fields propagated from the span the event is in,
+    /// unless the event has its own values for them.
+    pub(crate) fn inherit(&mut self, propagated: &Fields) {
+        self.fields.inherit(propagated);
+    }
+
+    /// Returns a hash of what identifies repeats of this event.
+    pub(crate) fn dedup_key(&self, dedup: Dedup) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.metadata.callsite().hash(&mut hasher);
+        if dedup == Dedup::CallsiteAndFields {
+            self.fields.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    pub(crate) fn is_repeat_of(&self, other: &BufferedEvent, dedup: Dedup) -> bool {
+        self.metadata.callsite() == other.metadata.callsite()
+            && (dedup == Dedup::Callsite || self.fields == other.fields)
+    }
+
+    /// Collapses a repeat of this event into it.
+    pub(crate) fn repeat(&mut self, repeat: BufferedEvent) {
+        self.repeated += repeat.repeated;
+        self.last_timestamp = repeat.last_timestamp;
+    }
+}
+
+impl fmt::Display for BufferedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.metadata.level(),
+            self.metadata.target(),
+            self.fields
+        )?;
+        if self.repeated > 1 {
+            write!(f, " repeated={}", self.repeated)?;
+        }
+        Ok(())
+    }
+}
+
+// ===== impl SpanRecord =====
+
+impl SpanRecord {
+    pub(crate) fn new(
+        id: SpanId,
+        metadata: &'static Metadata<'static>,
+        fields: Fields,
+        events: Vec<BufferedEvent>,
+        children: Vec<SpanRecord>,
+        start: SystemTime,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            id,
+            metadata,
+            fields,
+            events,
+            children,
+            start,
+            duration,
+        }
+    }
+
+    pub fn id(&self) -> SpanId {
+        self.id
+    }
+
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.metadata.name()
+    }
+
+    pub fn fields(&self) -> &Fields {
+        &self.fields
+    }
+
+    /// Returns the events recorded directly inside this span, oldest first.
+    pub fn events(&self) -> &[BufferedEvent] {
+        &self.events
+    }
+
+    /// Returns the records of this span's children, in the order they
+    /// closed.
+    pub fn children(&self) -> &[SpanRecord] {
+        &self.children
+    }
+
+    /// Returns the time at which the span was created.
+    pub fn start(&self) -> SystemTime {
+        self.start
+    }
+
+    /// Returns how long the span was open for.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub(crate) fn push_child(&mut self, child: SpanRecord) {
+        self.children.push(child);
+    }
+
+    /// Returns a copy of this span with no fields, events or children.
+    fn skeleton(&self) -> SpanRecord {
+        SpanRecord {
+            fields: Fields::new(),
+            events: Vec::new(),
+            children: Vec::new(),
+            ..*self
+        }
+    }
+
+    /// Writes the line describing this span, without a trailing newline.
+    fn fmt_line(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}{}", "", self.name(), indent = depth * 2)?;
+        if !self.fields.is_empty() {
+            write!(f, "{{{}}}", self.fields)?;
+        }
+        write!(f, " {:?} span_id={}", self.duration, self.id)
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        self.fmt_line(f, depth)?;
+        writeln!(f)?;
+        self.fmt_contents(f, depth)
+    }
+
+    fn fmt_contents(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{:indent$}{}", "", event, indent = (depth + 1) * 2)?;
+        }
+        for child in &self.children {
+            child.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// ===== impl ConcatRecord =====
+
+impl ConcatRecord {
+    pub(crate) fn new(root: SpanRecord, trace: Trace) -> Self {
+        Self {
+            id: RandomState::new().build_hasher().finish(),
+            trace,
+            root,
+            incomplete: false,
+            part: 1,
+            parts: 1,
+            snapshot: None,
+            whole: None,
+        }
+    }
+
+    /// Returns a record for a root span which has not closed yet.
+    pub(crate) fn incomplete(root: SpanRecord, trace: Trace) -> Self {
+        Self {
+            incomplete: true,
+            ..Self::new(root, trace)
+        }
+    }
+
+    /// Returns an identifier for this record, which is shared by all of its
+    /// parts if it was split.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the ID of the trace the root span belongs to.
+    pub fn trace_id(&self) -> TraceId {
+        self.trace.id
+    }
+
+    /// Returns the ID of the parent of the record's root span.
+    ///
+    /// This is the span in an upstream service which the root span continues,
+    /// if it had a `traceparent` field. When each span is flushed separately,
+    /// this is the parent span within the trace.
+    pub fn parent_span_id(&self) -> Option<SpanId> {
+        self.trace.parent
+    }
+
+    /// Returns the number of this part of the record, starting from 1, and
+    /// the total number of parts.
+    ///
+    /// A record is only split into several parts if it was larger than the
+    /// configured maximum record size.
+    pub fn part(&self) -> (usize, usize) {
+        (self.part, self.parts)
+    }
+
+    /// Returns the record of the root span.
+    pub fn root(&self) -> &SpanRecord {
+        &self.root
+    }
+
+    /// Returns the rate at which the root span was sampled, if it was.
+    ///
+    /// Each record stands for roughly `1 / rate` records which were not
+    /// kept, e.g. when estimating counts from sampled records.
+    pub fn sample_rate(&self) -> Option<f64> {
+        self.trace.sample_rate
+    }
+
+    /// Returns the sequence number of this record among the records flushed
+    /// for its root span, starting from 1, if heartbeat records were flushed
+    /// for it before it closed.
+    ///
+    /// Each heartbeat record only contains what was recorded since the
+    /// previous one, and the final record only contains what was recorded
+    /// since the last heartbeat.
+    pub fn snapshot(&self) -> Option<u64> {
+        self.snapshot
+    }
+
+    pub(crate) fn with_snapshot(self, snapshot: u64) -> Self {
+        Self {
+            snapshot: Some(snapshot),
+            ..self
+        }
+    }
+
+    /// Returns `true` if the root span had not closed yet when this record
+    /// was taken, so it may be missing data recorded later.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    /// Returns the level of the most severe event in the record, if it has
+    /// any events.
+    ///
+    /// For a part of a split record, this is the level of the most severe
+    /// event in the whole record, so that every part has the same level.
+    pub fn max_level(&self) -> Option<Level> {
+        if let Some(ref whole) = self.whole {
+            return whole.max_level;
+        }
+        fn max(span: &SpanRecord, level: &mut Option<Level>) {
+            for event in &span.events {
+                let event = *event.metadata().level();
+                let more_severe = match *level {
+                    Some(ref level) => severity(&event) > severity(level),
+                    None => true,
+                };
+                if more_severe {
+                    *level = Some(event);
+                }
+            }
+            for child in &span.children {
+                max(child, level);
+            }
+        }
+        let mut level = None;
+        max(&self.root, &mut level);
+        level
+    }
+
+    /// Returns the record's level: the most severe of the root span's level
+    /// and the levels of the events in the record.
+    ///
+    /// An `INFO` span containing an `ERROR` event results in an `ERROR`
+    /// record.
+    pub fn level(&self) -> Level {
+        let root = *self.root.metadata.level();
+        match self.max_level() {
+            Some(events) if severity(&events) > severity(&root) => events,
+            _ => root,
+        }
+    }
+
+    /// Returns the number of events in the record at the given level.
+    ///
+    /// Repeated events which were collapsed into one are counted as many
+    /// times as they occurred. For a part of a split record, the events in
+    /// the whole record are counted.
+    pub fn event_count(&self, level: Level) -> usize {
+        if let Some(ref whole) = self.whole {
+            return whole.event_counts[severity(&level) as usize];
+        }
+        fn count(span: &SpanRecord, level: &Level) -> usize {
+            let events = span
+                .events
+                .iter()
+                .filter(|event| event.metadata.level() == level)
+                .map(|event| event.repeated)
+                .sum::<usize>();
+            events
+                + span
+                    .children
+                    .iter()
+                    .map(|child| count(child, level))
+                    .sum::<usize>()
+        }
+        count(&self.root, &level)
+    }
+
+    pub fn warn_count(&self) -> usize {
+        self.event_count(Level::WARN)
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.event_count(Level::ERROR)
+    }
+
+    /// Returns the value of the first span field with the given name, looking
+    /// at the root span first and then its descendants.
+    pub fn span_field(&self, name: &str) -> Option<&Value> {
+        fn find<'a>(span: &'a SpanRecord, name: &str) -> Option<&'a Value> {
+            span.fields
+                .get(name)
+                .or_else(|| span.children.iter().find_map(|child| find(child, name)))
+        }
+        find(&self.root, name)
+    }
+
+    /// Splits the record into parts of at most `max_bytes` each, as measured
+    /// by `len`.
+    ///
+    /// Every part contains the root span and its fields, and the ancestors of
+    /// whatever else it contains, so that each part can be understood, and
+    /// routed by the root's fields, on its own. Any other span's fields are
+    /// only included in the part containing the start of that span. A single
+    /// span's fields or event which is larger than `max_bytes` is put in a
+    /// part of its own, rather than being split.
+    ///
+    /// Parts are first chosen using an estimate of each item's size. If any
+    /// part with more than one item turns out to be too large, the record is
+    /// split again with a proportionally smaller budget.
+    pub(crate) fn split(
+        self,
+        max_bytes: usize,
+        len: impl Fn(&ConcatRecord) -> usize,
+    ) -> Vec<ConcatRecord> {
+        // Visit the spans' fields and events in order, assigning each to the
+        // current part until it is full. Since parts are filled in order,
+        // each is a contiguous range of items. Every part but the first
+        // starts out with the cost of repeating the root's fields.
+        fn assign(
+            span: &SpanRecord,
+            max_bytes: usize,
+            root_cost: usize,
+            lens: &mut Vec<usize>,
+            size: &mut usize,
+        ) {
+            let mut add = |cost: usize| {
+                let last = *lens.last().expect("there is always a part");
+                if last > 0 && *size + cost > max_bytes {
+                    lens.push(0);
+                    *size = root_cost;
+                }
+                *lens.last_mut().expect("there is always a part") += 1;
+                *size += cost;
+            };
+            add(span_cost(span));
+            for event in &span.events {
+                add(event_cost(event));
+            }
+            for child in &span.children {
+                assign(child, max_bytes, root_cost, lens, size);
+            }
+        }
+
+        fn part(span: &SpanRecord, range: &Range<usize>, next: &mut usize) -> Option<SpanRecord> {
+            let first = *next;
+            *next += 1 + span.events.len();
+            let mut events = Vec::new();
+            for (i, event) in span.events.iter().enumerate() {
+                if range.contains(&(first + 1 + i)) {
+                    events.push(event.clone());
+                }
+            }
+            let children = span
+                .children
+                .iter()
+                .filter_map(|child| part(child, range, next))
+                .collect::<Vec<_>>();
+
+            // The root, and its fields, are in every part.
+            let fields = range.contains(&first) || first == 0;
+            let overlaps = fields || !events.is_empty() || !children.is_empty();
+            if !overlaps {
+                return None;
+            }
+            Some(SpanRecord {
+                fields: if fields {
+                    span.fields.clone()
+                } else {
+                    Fields::new()
+                },
+                events,
+                children,
+                ..span.skeleton()
+            })
+        }
+
+        // Each part only holds some of the events, so summarize them now.
+        let whole = Summary {
+            max_level: self.max_level(),
+            event_counts: [
+                Level::TRACE,
+                Level::DEBUG,
+                Level::INFO,
+                Level::WARN,
+                Level::ERROR,
+            ]
+            .map(|level| self.event_count(level)),
+        };
+        let mut budget = max_bytes;
+        loop {
+            let mut lens = vec![0];
+            assign(&self.root, budget, span_cost(&self.root), &mut lens, &mut 0);
+            let parts = if lens.len() == 1 {
+                vec![self.clone()]
+            } else {
+                let parts = lens.len();
+                let mut start = 0;
+                lens.iter()
+                    .enumerate()
+                    .map(|(i, len)| {
+                        let range = start..start + len;
+                        start += len;
+                        ConcatRecord {
+                            id: self.id,
+                            trace: self.trace,
+                            root: part(&self.root, &range, &mut 0)
+                                .expect("the root is in every part"),
+                            incomplete: self.incomplete,
+                            part: i + 1,
+                            parts,
+                            snapshot: self.snapshot,
+                            whole: Some(whole),
+                        }
+                    })
+                    .collect()
+            };
+
+            // Parts of a single item can't be split any further. Once the
+            // budget is 0, every part is a single item, so this terminates.
+            let largest = lens
+                .iter()
+                .zip(&parts)
+                .filter(|(&items, _)| items > 1)
+                .map(|(_, part)| len(part))
+                .max();
+            match largest {
+                Some(largest) if largest > max_bytes => {
+                    let shrunk = budget as u128 * max_bytes as u128 / largest as u128;
+                    budget = (shrunk as usize).min(budget.saturating_sub(1));
+                }
+                _ => return parts,
+            }
+        }
+    }
+
+    /// Removes every event in the record for which `f` returns `false`.
+    pub(crate) fn retain_events(&mut self, mut f: impl FnMut(&BufferedEvent) -> bool) {
+        fn retain(span: &mut SpanRecord, f: &mut dyn FnMut(&BufferedEvent) -> bool) {
+            span.events.retain(|event| f(event));
+            for child in &mut span.children {
+                retain(child, f);
+            }
+        }
+        retain(&mut self.root, &mut f)
+    }
+}
+
+impl fmt::Display for ConcatRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.parts > 1 {
+            writeln!(
+                f,
+                "[record {:016x}, part {}/{}]",
+                self.id, self.part, self.parts
+            )?;
+        }
+        self.root.fmt_line(f, 0)?;
+        write!(
+            f,
+            " trace_id={} level={} warn_count={} error_count={}",
+            self.trace.id,
+            self.level(),
+            self.warn_count(),
+            self.error_count()
+        )?;
+        if let Some(parent) = self.parent_span_id() {
+            write!(f, " parent_span_id={}", parent)?;
+        }
+        if let Some(rate) = self.sample_rate() {
+            write!(f, " sample_rate={}", rate)?;
+        }
+        if let Some(snapshot) = self.snapshot {
+            write!(f, " snapshot={}", snapshot)?;
+        }
+        if self.incomplete {
+            write!(f, " incomplete=true")?;
+        }
+        writeln!(f)?;
+        self.root.fmt_contents(f, 0)
+    }
+}
+
+/// Truncates string values longer than `max` bytes, marking them as
+/// truncated.
+fn truncate(value: &mut Value, max: usize) {
+    let string = match value {
+        Value::Str(string) | Value::Debug(string) => string,
+        _ => return,
+    };
+    if string.len() <= max {
+        return;
+    }
+    let mut end = max;
+    while !string.is_char_boundary(end) {
+        end -= 1;
+    }
+    let truncated = string.len() - end;
+    string.truncate(end);
+    string.push_str(&format!("...[{} bytes truncated]", truncated));
+}
+
+/// Estimates how many bytes a record takes up when written, independently of
+/// its format.
+pub(crate) fn estimated_len(record: &ConcatRecord) -> usize {
+    tree_size(&record.root).1
+}
+
+/// Counts the events in a span and its descendants, and estimates how many
+/// bytes they take up when written.
+pub(crate) fn tree_size(span: &SpanRecord) -> (usize, usize) {
+    let mut events = span.events.len();
+    let mut bytes = span_cost(span) + span.events.iter().map(event_cost).sum::<usize>();
+    for child in &span.children {
+        let (child_events, child_bytes) = tree_size(child);
+        events += child_events;
+        bytes += child_bytes;
+    }
+    (events, bytes)
+}
+
+/// Estimates how many bytes a span's name and fields take up when written.
+fn span_cost(span: &SpanRecord) -> usize {
+    span.name().len() + fields_cost(&span.fields) + 32
+}
+
+pub(crate) fn event_cost(event: &BufferedEvent) -> usize {
+    event.metadata.target().len() + fields_cost(&event.fields) + 32
+}
+
+pub(crate) fn fields_cost(fields: &Fields) -> usize {
+    fields
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Str(value) | Value::Debug(value) => value.len(),
+                _ => 20,
+            };
+            name.len() + value + 4
+        })
+        .sum()
+}
+
+/// Ranks levels by severity, from `TRACE` (least severe) to `ERROR`.
+///
+/// This doesn't rely on `Level`'s own ordering, whose direction is easy to get
+/// backwards.
+pub(crate) fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::TRACE => 0,
+        Level::DEBUG => 1,
+        Level::INFO => 2,
+        Level::WARN => 3,
+        Level::ERROR => 4,
+    }
+}
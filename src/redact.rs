@@ -0,0 +1,347 @@
+//! Redaction of sensitive field values before they are buffered.
+use std::{borrow::Cow, hash::Hasher};
+
+use regex::Regex;
+use siphasher::sip::SipHasher13;
+
+use crate::record::{Fields, Value};
+
+/// Rules for redacting sensitive data from span and event fields.
+///
+/// Redaction is applied when fields are recorded, before they are buffered,
+/// so redacted values never reach any sink, whatever its format.
+///
+/// Rules match either a field's name or its value. When a field's name
+/// matches, its whole value is redacted. When a value pattern matches, only
+/// the matching parts of the value are redacted, unless the action is
+/// [`Action::Drop`]. Name rules are checked before value rules, and the first
+/// matching name rule wins.
+///
+/// [`Action::Drop`]: enum.Action.html#variant.Drop
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    names: Vec<(NameMatcher, Action)>,
+    values: Vec<(Regex, Action)>,
+    key: Option<[u8; 16]>,
+}
+
+/// What to do with a value that matches a redaction rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Replace the value with `[REDACTED]`.
+    Mask,
+    /// Replace the value with a keyed hash of it, so that equal values can
+    /// still be correlated without revealing them.
+    Hash,
+    /// Remove the field entirely.
+    Drop,
+}
+
+#[derive(Clone, Debug)]
+enum NameMatcher {
+    Exact(String),
+    Pattern(Regex),
+}
+
+const MASK: &str = "[REDACTED]";
+
+// ===== impl Redactor =====
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redacts fields with exactly the given name.
+    pub fn name(mut self, name: impl Into<String>, action: Action) -> Self {
+        self.names.push((NameMatcher::Exact(name.into()), action));
+        self
+    }
+
+    /// Redacts fields whose names match a glob pattern, in which `*` matches
+    /// any number of characters and `?` matches a single character.
+    pub fn name_glob(mut self, glob: &str, action: Action) -> Self {
+        let mut pattern = String::from("^");
+        for c in glob.chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        pattern.push('$');
+        let regex = Regex::new(&pattern).expect("escaped glob is a valid regex");
+        self.names.push((NameMatcher::Pattern(regex), action));
+        self
+    }
+
+    /// Redacts fields whose names match a regular expression.
+    pub fn name_regex(mut self, regex: &str, action: Action) -> Result<Self, regex::Error> {
+        self.names
+            .push((NameMatcher::Pattern(Regex::new(regex)?), action));
+        Ok(self)
+    }
+
+    /// Redacts the parts of any field value which match a regular
+    /// expression.
+    pub fn value_pattern(mut self, regex: &str, action: Action) -> Result<Self, regex::Error> {
+        self.values.push((Regex::new(regex)?, action));
+        Ok(self)
+    }
+
+    /// Redacts anything that looks like a payment card number: 13 to 19
+    /// digits, optionally separated by spaces or dashes.
+    pub fn credit_cards(self, action: Action) -> Self {
+        self.value_pattern(r"\b(?:\d[ -]?){12,18}\d\b", action)
+            .expect("built-in pattern is valid")
+    }
+
+    /// Redacts bearer tokens, as found in HTTP `Authorization` headers.
+    pub fn bearer_tokens(self, action: Action) -> Self {
+        self.value_pattern(r"(?i)\bbearer\s+[A-Za-z0-9\-._~+/]+=*", action)
+            .expect("built-in pattern is valid")
+    }
+
+    /// Sets the key used by [`Action::Hash`].
+    ///
+    /// Without a key, a random one is chosen per `Redactor`, so hashes can
+    /// only be correlated within a single process.
+    ///
+    /// [`Action::Hash`]: enum.Action.html#variant.Hash
+    pub fn with_hash_key(self, key: [u8; 16]) -> Self {
+        Self {
+            key: Some(key),
+            ..self
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.values.is_empty()
+    }
+
+    /// Chooses a random hash key, if one was not set.
+    pub(crate) fn init(&mut self) {
+        if self.key.is_none() {
+            self.key = Some(rand::random::<[u8; 16]>());
+        }
+    }
+
+    /// Applies the redaction rules to `fields`.
+    pub(crate) fn redact(&self, fields: &mut Fields) {
+        fields.retain_mut(|name, value| match self.name_action(name) {
+            Some(Action::Drop) => false,
+            Some(Action::Mask) => {
+                *value = Value::Str(MASK.to_owned());
+                true
+            }
+            Some(Action::Hash) => {
+                *value = Value::Str(self.hash(&value_str(value)));
+                true
+            }
+            None => self.redact_value(value),
+        })
+    }
+
+    fn name_action(&self, name: &str) -> Option<Action> {
+        self.names
+            .iter()
+            .find(|(matcher, _)| match matcher {
+                NameMatcher::Exact(exact) => exact == name,
+                NameMatcher::Pattern(regex) => regex.is_match(name),
+            })
+            .map(|(_, action)| *action)
+    }
+
+    /// Redacts the parts of `value` matching any value pattern, returning
+    /// `false` if the field should be dropped.
+    fn redact_value(&self, value: &mut Value) -> bool {
+        if self.values.is_empty() {
+            return true;
+        }
+
+        let mut redacted: Option<String> = None;
+        for (regex, action) in &self.values {
+            let current = match redacted {
+                Some(ref redacted) => Cow::Borrowed(redacted.as_str()),
+                None => value_str(value),
+            };
+            if !regex.is_match(&current) {
+                continue;
+            }
+            let replaced = match action {
+                Action::Drop => return false,
+                Action::Mask => regex.replace_all(&current, MASK),
+                Action::Hash => regex.replace_all(&current, |captures: &regex::Captures<'_>| {
+                    self.hash(&captures[0])
+                }),
+            };
+            redacted = Some(replaced.into_owned());
+        }
+
+        if let Some(redacted) = redacted {
+            *value = match value {
+                Value::Debug(_) => Value::Debug(redacted),
+                _ => Value::Str(redacted),
+            };
+        }
+        true
+    }
+
+    fn hash(&self, value: &str) -> String {
+        let mut hasher = SipHasher13::new_with_key(&self.key.unwrap_or_default());
+        hasher.write(value.as_bytes());
+        format!("hash:{:016x}", hasher.finish())
+    }
+}
+
+/// Returns the string that value patterns are matched against.
+fn value_str(value: &Value) -> Cow<'_, str> {
+    match value {
+        Value::Str(value) | Value::Debug(value) => Cow::Borrowed(value),
+        value => Cow::Owned(value.to_string()),
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    fn redact(redactor: &Redactor, fields: &[(&'static str, Value)]) -> Fields {
+        let mut redacted = Fields::new();
+        for (name, value) in fields {
+            redacted.insert(name, value.clone());
+        }
+        redactor.redact(&mut redacted);
+        redacted
+    }
+
+    fn str(value: &str) -> Value {
+        Value::Str(value.to_owned())
+    }
+
+    #[test]
+    fn name_glob() {
+        let redactor = Redactor::new().name_glob("*.pass?ord", Action::Mask);
+        let fields = redact(
+            &redactor,
+            &[
+                ("user.password", str("hunter2")),
+                ("user.passsword", str("kept")),
+                ("password", str("kept")),
+                ("user.password.len", Value::U64(7)),
+            ],
+        );
+        assert_eq!(fields.get("user.password"), Some(&str(MASK)));
+        assert_eq!(fields.get("user.passsword"), Some(&str("kept")));
+        assert_eq!(fields.get("password"), Some(&str("kept")));
+        assert_eq!(fields.get("user.password.len"), Some(&Value::U64(7)));
+    }
+
+    #[test]
+    fn name_glob_escapes_regex_syntax() {
+        let redactor = Redactor::new().name_glob("a.b+", Action::Mask);
+        let fields = redact(&redactor, &[("a.b+", str("x")), ("axbb", str("y"))]);
+        assert_eq!(fields.get("a.b+"), Some(&str(MASK)));
+        assert_eq!(fields.get("axbb"), Some(&str("y")));
+    }
+
+    #[test]
+    fn credit_cards() {
+        let redactor = Redactor::new().credit_cards(Action::Mask);
+        let fields = redact(
+            &redactor,
+            &[
+                ("plain", str("card 4111111111111111 declined")),
+                ("spaced", str("4111 1111 1111 1111")),
+                ("dashed", str("4111-1111-1111-1111")),
+                ("short", str("order 123456789012")),
+            ],
+        );
+        assert_eq!(fields.get("plain"), Some(&str("card [REDACTED] declined")));
+        assert_eq!(fields.get("spaced"), Some(&str(MASK)));
+        assert_eq!(fields.get("dashed"), Some(&str(MASK)));
+        assert_eq!(fields.get("short"), Some(&str("order 123456789012")));
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        let redactor = Redactor::new().bearer_tokens(Action::Mask);
+        let fields = redact(
+            &redactor,
+            &[
+                ("header", str("Authorization: Bearer abc.DEF-123_~+/==")),
+                ("lower", Value::Debug("\"bearer xyz\"".to_owned())),
+                ("other", str("bearer")),
+            ],
+        );
+        assert_eq!(
+            fields.get("header"),
+            Some(&str("Authorization: [REDACTED]"))
+        );
+        assert_eq!(
+            fields.get("lower"),
+            Some(&Value::Debug("\"[REDACTED]\"".to_owned()))
+        );
+        assert_eq!(fields.get("other"), Some(&str("bearer")));
+    }
+
+    #[test]
+    fn actions() {
+        let redactor = Redactor::new()
+            .name("masked", Action::Mask)
+            .name("hashed", Action::Hash)
+            .name("dropped", Action::Drop)
+            .with_hash_key([7; 16]);
+        let fields = redact(
+            &redactor,
+            &[
+                ("masked", str("secret")),
+                ("hashed", str("secret")),
+                ("dropped", str("secret")),
+                ("kept", str("secret")),
+            ],
+        );
+        assert_eq!(fields.get("masked"), Some(&str(MASK)));
+        assert_eq!(
+            fields.get("hashed"),
+            Some(&Value::Str(redactor.hash("secret")))
+        );
+        assert_eq!(fields.get("dropped"), None);
+        assert_eq!(fields.get("kept"), Some(&str("secret")));
+    }
+
+    #[test]
+    fn value_actions() {
+        let pattern = r"\bsk_[a-z0-9]+";
+        let fields = [("msg", str("key sk_abc123 used"))];
+        let hash = Redactor::new()
+            .value_pattern(pattern, Action::Hash)
+            .unwrap()
+            .with_hash_key([7; 16]);
+        let expected = format!("key {} used", hash.hash("sk_abc123"));
+        assert_eq!(
+            redact(&hash, &fields).get("msg"),
+            Some(&Value::Str(expected))
+        );
+
+        let drop = Redactor::new()
+            .value_pattern(pattern, Action::Drop)
+            .unwrap();
+        assert!(redact(&drop, &fields).is_empty());
+    }
+
+    #[test]
+    fn hash_is_keyed() {
+        let key = |key| Redactor::new().with_hash_key(key);
+        assert_eq!(key([1; 16]).hash("secret"), key([1; 16]).hash("secret"));
+        assert_ne!(key([1; 16]).hash("secret"), key([2; 16]).hash("secret"));
+        assert_ne!(key([1; 16]).hash("secret"), key([1; 16]).hash("other"));
+        assert!(key([1; 16]).hash("secret").starts_with("hash:"));
+
+        let mut random = Redactor::new();
+        random.init();
+        let mut other = Redactor::new();
+        other.init();
+        assert_ne!(random.key, other.key);
+    }
+}
@@ -0,0 +1,151 @@
+//! Records and events reported by this crate itself, rather than by the
+//! application.
+use std::time::{Duration, SystemTime};
+
+use tracing_core::{callsite::Callsite, metadata::Kind, subscriber::Interest, Level, Metadata};
+
+use crate::{
+    record::{BufferedEvent, ConcatRecord, Fields, SpanRecord, Value},
+    trace::{SpanId, Trace},
+};
+
+/// Defines the static metadata of a span or event reported by this crate,
+/// with the given name, level and field names.
+macro_rules! report {
+    ($callsite:ident, $metadata:ident, $kind:expr, $name:expr, $level:expr, $fields:expr) => {
+        struct $callsite;
+
+        impl Callsite for $callsite {
+            fn set_interest(&self, _: Interest) {}
+
+            fn metadata(&self) -> &Metadata<'_> {
+                &$metadata
+            }
+        }
+
+        static $metadata: Metadata<'static> = tracing_core::metadata! {
+            name: $name,
+            target: "tracing_concat",
+            level: $level,
+            fields: $fields,
+            callsite: &$callsite,
+            kind: $kind,
+        };
+    };
+}
+
+report!(
+    RateLimitedCallsite,
+    RATE_LIMITED,
+    Kind::SPAN,
+    "rate_limited",
+    Level::WARN,
+    &["span", "suppressed"]
+);
+
+report!(
+    LeakedSpansCallsite,
+    LEAKED_SPANS,
+    Kind::SPAN,
+    "leaked_spans",
+    Level::WARN,
+    &["count", "older_than_ms"]
+);
+
+report!(
+    SpanMisuseCallsite,
+    SPAN_MISUSE,
+    Kind::SPAN,
+    "span_misuse",
+    Level::WARN,
+    &["kind", "span", "callsite", "current", "current_callsite"]
+);
+
+report!(
+    CaptureCallsite,
+    CAPTURE,
+    Kind::SPAN,
+    "capture",
+    Level::INFO,
+    &[]
+);
+
+report!(
+    PanicCallsite,
+    PANIC,
+    Kind::EVENT,
+    "panic",
+    Level::ERROR,
+    &["message", "location"]
+);
+
+/// Returns the metadata of the root span which `capture` runs its closure
+/// in.
+pub(crate) fn capture() -> &'static Metadata<'static> {
+    &CAPTURE
+}
+
+/// Returns an empty record of a `capture` span, for when the span could not
+/// be recorded, e.g. because the slab was full.
+pub(crate) fn capture_failed() -> ConcatRecord {
+    report(&CAPTURE, Fields::new(), SystemTime::now(), Vec::new())
+}
+
+/// Returns an event reporting that the thread panicked, with the given
+/// message and location.
+pub(crate) fn panic(fields: Fields) -> BufferedEvent {
+    BufferedEvent::new(&PANIC, fields)
+}
+
+/// Returns a record reporting that `suppressed` records of root spans named
+/// `span` were not flushed, because they exceeded its rate limit, during the
+/// period beginning at `since`.
+pub(crate) fn rate_limited(span: &str, suppressed: u64, since: SystemTime) -> ConcatRecord {
+    let mut fields = Fields::new();
+    fields.insert("span", Value::Str(span.to_owned()));
+    fields.insert("suppressed", Value::U64(suppressed));
+    report(&RATE_LIMITED, fields, since, Vec::new())
+}
+
+/// Returns a record reporting that `spans` have been open for longer than
+/// `older_than`, and may have been leaked.
+pub(crate) fn leaked_spans(older_than: Duration, spans: Vec<SpanRecord>) -> ConcatRecord {
+    let mut fields = Fields::new();
+    fields.insert("count", Value::U64(spans.len() as u64));
+    fields.insert("older_than_ms", Value::U64(older_than.as_millis() as u64));
+    report(&LEAKED_SPANS, fields, SystemTime::now(), spans)
+}
+
+/// Returns a record reporting that a span was misused, e.g. exited while
+/// it was not the current span.
+pub(crate) fn span_misuse(fields: Fields) -> ConcatRecord {
+    report(&SPAN_MISUSE, fields, SystemTime::now(), Vec::new())
+}
+
+/// Returns the file and line that a span or event was created on, or its
+/// target if they are unknown.
+pub(crate) fn callsite(metadata: &Metadata<'_>) -> String {
+    match (metadata.file(), metadata.line()) {
+        (Some(file), Some(line)) => format!("{}:{}", file, line),
+        _ => metadata.target().to_owned(),
+    }
+}
+
+fn report(
+    metadata: &'static Metadata<'static>,
+    fields: Fields,
+    since: SystemTime,
+    children: Vec<SpanRecord>,
+) -> ConcatRecord {
+    let duration = since.elapsed().unwrap_or(Duration::from_secs(0));
+    let root = SpanRecord::new(
+        SpanId::random(),
+        metadata,
+        fields,
+        Vec::new(),
+        children,
+        since,
+        duration,
+    );
+    ConcatRecord::new(root, Trace::random())
+}
@@ -0,0 +1,428 @@
+//! Destinations for concatenated records.
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use tracing::Level;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    format::{FormatRecord, Tree},
+    record::{self, severity, ConcatRecord, Value},
+};
+
+/// Receives the concatenated record of each root span when it closes.
+///
+/// Any `Fn(&ConcatRecord)` closure is a sink.
+pub trait ConcatSink: Send + Sync + 'static {
+    fn on_flush(&self, record: &ConcatRecord);
+
+    /// Writes out any records which the sink has buffered, e.g. because the
+    /// process is about to exit.
+    ///
+    /// By default, this does nothing.
+    fn flush(&self) {}
+
+    /// Returns how many bytes the sink would write for `record`, if it knows.
+    ///
+    /// This is used to keep each part of a [split] record within the limit.
+    /// By default, this returns `None`, and the record's size is estimated
+    /// from its fields instead.
+    ///
+    /// [split]: struct.Builder.html#method.with_max_record_bytes
+    fn record_len(&self, record: &ConcatRecord) -> Option<usize> {
+        let _ = record;
+        None
+    }
+}
+
+/// A sink which formats records and writes them to an `io::Write`r.
+///
+/// Events can be filtered by level and target, independently of any other
+/// sinks. Spans are always written, even if all of their events were
+/// filtered out.
+pub struct WriterSink<F = Tree, W = fn() -> io::Stdout> {
+    format: F,
+    make_writer: W,
+    max_level: Option<Level>,
+    targets: Option<Vec<String>>,
+}
+
+/// A sink which passes each record on to one of several other sinks, chosen
+/// by the value of a span field or by the record's level.
+///
+/// Records can additionally be copied to other sinks based on their level,
+/// e.g. to keep every record containing an error in a separate file,
+/// regardless of where it was routed.
+pub struct RoutingSink {
+    key: RouteKey,
+    routes: HashMap<String, Box<dyn ConcatSink>>,
+    make_route: Option<Box<MakeRoute>>,
+    // Sinks created by `make_route`, by key.
+    made: RwLock<HashMap<String, Arc<dyn ConcatSink>>>,
+    fallback: Option<Box<dyn ConcatSink>>,
+    tees: Vec<(Level, Box<dyn ConcatSink>)>,
+}
+
+/// Passes the records of root spans on to the configured sinks.
+pub(crate) struct Flusher {
+    pub(crate) sinks: Vec<Box<dyn ConcatSink>>,
+    pub(crate) max_record_bytes: Option<usize>,
+    // The number of records passed to the sinks.
+    pub(crate) flushed: AtomicUsize,
+}
+
+/// An `io::Write`r which counts the bytes written to it, and discards them.
+struct Count(usize);
+
+type MakeRoute = dyn Fn(&str) -> Option<Box<dyn ConcatSink>> + Send + Sync;
+
+#[derive(Debug)]
+enum RouteKey {
+    Field(String),
+    Level,
+}
+
+impl<F> ConcatSink for F
+where
+    F: Fn(&ConcatRecord) + Send + Sync + 'static,
+{
+    fn on_flush(&self, record: &ConcatRecord) {
+        (self)(record)
+    }
+}
+
+// ===== impl WriterSink =====
+
+impl Default for WriterSink {
+    fn default() -> Self {
+        Self::new(Tree, io::stdout)
+    }
+}
+
+impl<F, W> WriterSink<F, W>
+where
+    F: FormatRecord,
+    W: MakeWriter + Send + Sync + 'static,
+{
+    pub fn new(format: F, make_writer: W) -> Self {
+        Self {
+            format,
+            make_writer,
+            max_level: None,
+            targets: None,
+        }
+    }
+
+    /// Only writes events at `level` or more severe.
+    pub fn with_max_level(self, level: Level) -> Self {
+        Self {
+            max_level: Some(level),
+            ..self
+        }
+    }
+
+    /// Only writes events whose target starts with one of `targets`.
+    pub fn with_targets<I>(self, targets: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Self {
+            targets: Some(targets.into_iter().map(Into::into).collect()),
+            ..self
+        }
+    }
+
+    /// Removes the events which this sink does not write from `record`.
+    fn filter<'a>(&self, record: &'a ConcatRecord) -> Cow<'a, ConcatRecord> {
+        if self.max_level.is_none() && self.targets.is_none() {
+            return Cow::Borrowed(record);
+        }
+        let mut record = record.clone();
+        record.retain_events(|event| {
+            let meta = event.metadata();
+            let level = match self.max_level {
+                Some(max) => severity(meta.level()) >= severity(&max),
+                None => true,
+            };
+            let target = match self.targets {
+                Some(ref targets) => targets
+                    .iter()
+                    .any(|target| meta.target().starts_with(target.as_str())),
+                None => true,
+            };
+            level && target
+        });
+        Cow::Owned(record)
+    }
+}
+
+impl<F, W> ConcatSink for WriterSink<F, W>
+where
+    F: FormatRecord,
+    W: MakeWriter + Send + Sync + 'static,
+{
+    fn on_flush(&self, record: &ConcatRecord) {
+        let record = self.filter(record);
+        let mut writer = self.make_writer.make_writer();
+        // There's nowhere to report a failure to write logs to.
+        let _ = self.format.format_record(&record, &mut writer);
+    }
+
+    fn record_len(&self, record: &ConcatRecord) -> Option<usize> {
+        let mut count = Count(0);
+        self.format
+            .format_record(&self.filter(record), &mut count)
+            .ok()?;
+        Some(count.0)
+    }
+
+    fn flush(&self) {
+        let _ = self.make_writer.make_writer().flush();
+    }
+}
+
+// ===== impl Count =====
+
+impl Write for Count {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// ===== impl Flusher =====
+
+impl Flusher {
+    pub(crate) fn flush(&self, record: ConcatRecord) {
+        self.flushed.fetch_add(1, Ordering::Relaxed);
+        match self.max_record_bytes {
+            Some(max) => {
+                for part in record.split(max, |part| self.record_len(part)) {
+                    self.send(&part);
+                }
+            }
+            None => self.send(&record),
+        }
+    }
+
+    /// Flushes every sink.
+    pub(crate) fn finish(&self) {
+        for sink in &self.sinks {
+            sink.flush();
+        }
+    }
+
+    /// Returns the most bytes that any sink would write for `record`.
+    fn record_len(&self, record: &ConcatRecord) -> usize {
+        self.sinks
+            .iter()
+            .filter_map(|sink| sink.record_len(record))
+            .max()
+            .unwrap_or_else(|| record::estimated_len(record))
+    }
+
+    fn send(&self, record: &ConcatRecord) {
+        for sink in &self.sinks {
+            sink.on_flush(record);
+        }
+    }
+}
+
+// ===== impl RoutingSink =====
+
+impl RoutingSink {
+    /// Routes records by the value of the span field with the given name.
+    ///
+    /// The field is looked up on the root span first, and then on its
+    /// descendants. String values are used as keys as-is; other values are
+    /// formatted.
+    ///
+    /// Every part of a [split] record repeats the root's fields, so if the
+    /// field is on the root span, all the parts go to the same sink.
+    ///
+    /// [split]: ../struct.Builder.html#method.with_max_record_bytes
+    pub fn by_field(name: impl Into<String>) -> Self {
+        Self::new(RouteKey::Field(name.into()))
+    }
+
+    /// Routes records by their [level].
+    ///
+    /// Keys are level names, as formatted by `Level`'s `Display`
+    /// implementation, e.g. `"ERROR"`.
+    ///
+    /// [level]: ../struct.ConcatRecord.html#method.level
+    pub fn by_level() -> Self {
+        Self::new(RouteKey::Level)
+    }
+
+    fn new(key: RouteKey) -> Self {
+        Self {
+            key,
+            routes: HashMap::new(),
+            make_route: None,
+            made: RwLock::new(HashMap::new()),
+            fallback: None,
+            tees: Vec::new(),
+        }
+    }
+
+    /// Sends records with the given key to `sink`.
+    pub fn route(mut self, key: impl fmt::Display, sink: impl ConcatSink) -> Self {
+        self.routes.insert(key.to_string(), Box::new(sink));
+        self
+    }
+
+    /// Creates sinks on demand for keys which have no route.
+    ///
+    /// `f` is called the first time a record with a new key is flushed, and
+    /// the sink it returns is used for every later record with that key. If
+    /// it returns `None`, records with that key go to the fallback sink.
+    pub fn route_with<F>(self, f: F) -> Self
+    where
+        F: Fn(&str) -> Option<Box<dyn ConcatSink>> + Send + Sync + 'static,
+    {
+        Self {
+            make_route: Some(Box::new(f)),
+            ..self
+        }
+    }
+
+    /// Sends records which have no route, or no key at all, to `sink`.
+    ///
+    /// Without a fallback, such records are dropped.
+    pub fn fallback(self, sink: impl ConcatSink) -> Self {
+        Self {
+            fallback: Some(Box::new(sink)),
+            ..self
+        }
+    }
+
+    /// Also sends records whose level is `level` or more severe to `sink`, in
+    /// addition to wherever they are routed.
+    pub fn tee(mut self, level: Level, sink: impl ConcatSink) -> Self {
+        self.tees.push((level, Box::new(sink)));
+        self
+    }
+
+    fn key(&self, record: &ConcatRecord) -> Option<String> {
+        match self.key {
+            RouteKey::Field(ref name) => match record.span_field(name)? {
+                Value::Str(value) => Some(value.clone()),
+                value => Some(value.to_string()),
+            },
+            RouteKey::Level => Some(record.level().to_string()),
+        }
+    }
+
+    fn made_route(&self, key: &str, make_route: &MakeRoute) -> Option<Arc<dyn ConcatSink>> {
+        let made = self.made.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(sink) = made.get(key) {
+            return Some(sink.clone());
+        }
+        drop(made);
+
+        let mut made = self.made.write().unwrap_or_else(|e| e.into_inner());
+        // Another thread may have made a sink for this key while we weren't
+        // holding the lock.
+        if let Some(sink) = made.get(key) {
+            return Some(sink.clone());
+        }
+        let sink: Arc<dyn ConcatSink> = make_route(key)?.into();
+        made.insert(key.to_owned(), sink.clone());
+        Some(sink)
+    }
+}
+
+impl ConcatSink for RoutingSink {
+    fn on_flush(&self, record: &ConcatRecord) {
+        let key = self.key(record);
+        let routed = key.as_ref().and_then(|key| {
+            if let Some(sink) = self.routes.get(key) {
+                sink.on_flush(record);
+                return Some(());
+            }
+            let sink = self.made_route(key, self.make_route.as_ref()?)?;
+            sink.on_flush(record);
+            Some(())
+        });
+        if routed.is_none() {
+            if let Some(ref fallback) = self.fallback {
+                fallback.on_flush(record);
+            }
+        }
+
+        let record_level = record.level();
+        for (level, sink) in &self.tees {
+            if severity(&record_level) >= severity(level) {
+                sink.on_flush(record);
+            }
+        }
+    }
+
+    fn record_len(&self, record: &ConcatRecord) -> Option<usize> {
+        // The parts of a record may be routed differently, e.g. by level, so
+        // measure it for every sink it could go to.
+        let made = self.made.read().unwrap_or_else(|e| e.into_inner());
+        let sinks = self
+            .routes
+            .values()
+            .chain(&self.fallback)
+            .chain(self.tees.iter().map(|(_, sink)| sink));
+        sinks
+            .filter_map(|sink| sink.record_len(record))
+            .chain(made.values().filter_map(|sink| sink.record_len(record)))
+            .max()
+    }
+
+    fn flush(&self) {
+        let made = self.made.read().unwrap_or_else(|e| e.into_inner());
+        let sinks = self
+            .routes
+            .values()
+            .chain(&self.fallback)
+            .chain(self.tees.iter().map(|(_, sink)| sink));
+        for sink in sinks {
+            sink.flush();
+        }
+        for sink in made.values() {
+            sink.flush();
+        }
+    }
+}
+
+impl fmt::Debug for RoutingSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoutingSink")
+            .field("key", &self.key)
+            .field("routes", &self.routes.keys().collect::<Vec<_>>())
+            .field("fallback", &self.fallback.is_some())
+            .field(
+                "tees",
+                &self.tees.iter().map(|(level, _)| level).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<F: fmt::Debug, W> fmt::Debug for WriterSink<F, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriterSink")
+            .field("format", &self.format)
+            .field("max_level", &self.max_level)
+            .field("targets", &self.targets)
+            .finish()
+    }
+}
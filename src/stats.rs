@@ -0,0 +1,98 @@
+/// A snapshot of runtime statistics about the spans tracked by
+/// [`TracingConcat`].
+///
+/// [`TracingConcat`]: struct.TracingConcat.html
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub(crate) lock_poison_recoveries: usize,
+    pub(crate) span_misuses: usize,
+    pub(crate) live_spans: usize,
+    pub(crate) slab_capacity: usize,
+    pub(crate) free_slots: usize,
+    pub(crate) buffered_events: usize,
+    pub(crate) buffered_bytes: usize,
+    pub(crate) records_flushed: usize,
+    pub(crate) records_rate_limited: usize,
+    pub(crate) records_sampled_out: usize,
+    pub(crate) events_outside_spans: usize,
+    pub(crate) events_filtered: usize,
+    pub(crate) events_sampled_out: usize,
+}
+
+impl Stats {
+    /// Returns the number of times a lock on a span's data was poisoned by a
+    /// thread panicking while holding it, and was recovered.
+    pub fn lock_poison_recoveries(&self) -> usize {
+        self.lock_poison_recoveries
+    }
+
+    /// Returns the number of times a span was misused, e.g. exited while it
+    /// was not the current span.
+    pub fn span_misuses(&self) -> usize {
+        self.span_misuses
+    }
+
+    /// Returns the number of spans which are currently open.
+    pub fn live_spans(&self) -> usize {
+        self.live_spans
+    }
+
+    /// Returns the number of slots allocated for spans, whether or not they
+    /// are in use.
+    pub fn slab_capacity(&self) -> usize {
+        self.slab_capacity
+    }
+
+    /// Returns the number of slots which have been used by a span that has
+    /// since closed, and are free to be reused.
+    pub fn free_slots(&self) -> usize {
+        self.free_slots
+    }
+
+    /// Returns the number of events buffered in open spans, waiting to be
+    /// flushed.
+    pub fn buffered_events(&self) -> usize {
+        self.buffered_events
+    }
+
+    /// Returns an estimate of the number of bytes the buffered spans and
+    /// events will take up when they are written.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Returns the number of records which have been passed to the sinks.
+    pub fn records_flushed(&self) -> usize {
+        self.records_flushed
+    }
+
+    /// Returns the number of records which were dropped because they
+    /// exceeded the rate limit of their root span.
+    pub fn records_rate_limited(&self) -> usize {
+        self.records_rate_limited
+    }
+
+    /// Returns the number of records which were dropped because their trace
+    /// was not sampled.
+    pub fn records_sampled_out(&self) -> usize {
+        self.records_sampled_out
+    }
+
+    /// Returns the number of events which were dropped because they occurred
+    /// outside of any span.
+    pub fn events_outside_spans(&self) -> usize {
+        self.events_outside_spans
+    }
+
+    /// Returns the number of events which were dropped because their level
+    /// was filtered out.
+    pub fn events_filtered(&self) -> usize {
+        self.events_filtered
+    }
+
+    /// Returns the number of events which were dropped because their trace
+    /// was not sampled.
+    pub fn events_sampled_out(&self) -> usize {
+        self.events_sampled_out
+    }
+}
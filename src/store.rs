@@ -1,83 +1,219 @@
 use std::{
     cell::RefCell,
     fmt, mem, str,
-    sync::atomic::{self, AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
-use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use owning_ref::OwningHandle;
+use crate::sync::{
+    self, AtomicUsize, Ordering, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
 
-use std::collections::HashSet;
+use crate::{
+    limit::RateLimits,
+    record::{
+        event_cost, fields_cost, severity, tree_size, BufferedEvent, ConcatRecord, Dedup, Fields,
+        Recorder, SpanRecord, Value,
+    },
+    report,
+    stats::Stats,
+    trace::{SpanId, Trace, TraceId},
+};
+use std::collections::{HashMap, HashSet};
 pub(crate) use tracing_core::span::{Attributes, Id, Record};
-use tracing_core::{dispatcher, Metadata};
-use tracing_subscriber::fmt::format::{FormatFields};
-use tracing_subscriber::field::RecordFields;
-
-
-#[macro_use]
-macro_rules! try_lock {
-    ($lock:expr) => {
-        try_lock!($lock, else return)
-    };
-    ($lock:expr, else $els:expr) => {
-        match $lock {
-            Ok(l) => l,
-            Err(_) if std::thread::panicking() => $els,
-            Err(_) => panic!("lock poisoned"),
-        }
-    };
-}
+use tracing_core::{Event, Level, Metadata};
+
+pub(crate) mod slab;
+use self::slab::Slab;
 
-pub struct Span<'a> {
-    lock: OwningHandle<RwLockReadGuard<'a, Slab>, RwLockReadGuard<'a, Slot>>,
+#[cfg(test)]
+mod tests;
+
+/// A copy of an open span's data in the `Store`, as it was when the copy was
+/// taken.
+///
+/// A `Span` holds no lock on the span it was copied from, so the span may
+/// record fields or events, or close, while the copy is in use.
+#[derive(Clone, Debug)]
+pub struct Span {
+    metadata: &'static Metadata<'static>,
+    span_id: SpanId,
+    trace_id: Option<TraceId>,
+    parent: Option<Id>,
+    fields: Fields,
+    events: Vec<BufferedEvent>,
+    children: Vec<SpanRecord>,
 }
 
-/// Represents the `Subscriber`'s view of the current span context to a
-/// formatter.
+/// Represents the `Subscriber`'s view of the current span context.
 #[derive(Debug)]
-pub struct Context<'a, F> {
+pub(crate) struct Context<'a> {
     store: &'a Store,
-    fmt_fields: &'a F,
 }
 
 /// Stores data associated with currently-active spans.
-#[derive(Debug)]
 pub(crate) struct Store {
     // Active span data is stored in a slab of span slots. Each slot has its own
     // read-write lock to guard against concurrent modification to its data.
-    // Thus, we can modify any individual slot by acquiring a read lock on the
-    // slab, and using that lock to acquire a write lock on the slot we wish to
-    // modify. It is only necessary to acquire the write lock here when the
-    // slab itself has to be modified (i.e., to allocate more slots).
-    inner: RwLock<Slab>,
-
-    // The head of the slab's "free list".
-    next: AtomicUsize,
+    // The slab itself is lock-free: it grows a page at a time, and pages are
+    // never moved once allocated, so modifying a slot only ever requires
+    // locking that slot.
+    inner: Slab<Slot>,
+
+    // The number of times a slot lock was found poisoned (because a thread
+    // panicked while holding it) and recovered.
+    poison_recoveries: AtomicUsize,
+
+    // The number of times a span was misused, e.g. exited while it was not
+    // the current span.
+    misuses: AtomicUsize,
+
+    // The number of records and events which were dropped, by reason.
+    records_sampled_out: AtomicUsize,
+    records_rate_limited: AtomicUsize,
+    events_outside_spans: AtomicUsize,
+    events_filtered: AtomicUsize,
+    events_sampled_out: AtomicUsize,
+
+    // Called with each record when it is complete.
+    flush: Flush,
+
+    config: Config,
+}
+
+/// Options controlling what the store records, and when it flushes records.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    pub(crate) recorder: Recorder,
+    pub(crate) flush_mode: FlushMode,
+    // The names of fields which are copied from spans into their descendant
+    // spans and events.
+    pub(crate) propagated: Vec<String>,
+    // The least severe level of spans and events which are recorded, unless a
+    // root span overrides it.
+    pub(crate) max_level: Option<Level>,
+    // The name of the root span field which overrides `max_level` for its
+    // span tree.
+    pub(crate) level_field: String,
+    // The rate at which root spans are sampled, and the rates for root spans
+    // with particular names, which take precedence.
+    pub(crate) sample_rate: Option<f64>,
+    pub(crate) span_sample_rates: Vec<(String, f64)>,
+    // When heartbeat records are flushed for root spans with particular
+    // names.
+    pub(crate) heartbeats: Vec<(String, Heartbeat)>,
+    // Limits on how many records of root spans with particular names are
+    // flushed.
+    pub(crate) rate_limits: RateLimits,
+    // Whether to flush a record reporting each misuse of a span.
+    pub(crate) strict: bool,
+}
+
+/// When records are flushed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Each span's record is added to its parent's record when it closes,
+    /// and one record for the whole tree is flushed when the root span
+    /// closes.
+    #[default]
+    Root,
+    /// Each span's record is flushed on its own when it closes, without the
+    /// records of its children.
+    ///
+    /// Spans inside a [captured] span are the exception: their records are
+    /// kept in the captured record, as with `Root`.
+    ///
+    /// [captured]: ../fn.capture.html
+    EachSpan,
+}
+
+/// When to flush heartbeat records for a long-lived root span, such as a
+/// connection or a background worker.
+///
+/// A heartbeat record contains the events recorded in the root span, and the
+/// records of its children which closed, since the previous heartbeat. They
+/// are then cleared from the buffer. It is
+/// marked as incomplete, and numbered with its [`snapshot`].
+///
+/// Heartbeats are checked whenever an event is recorded directly inside the
+/// root span or one of its children closes, and by [`Handle::flush_due`].
+/// Events inside the root's descendants don't trigger a check, so a span
+/// which records nothing directly for longer than the interval only gets a
+/// heartbeat if `flush_due` is called, e.g. periodically from a background
+/// task.
+///
+/// Roots whose trace was sampled out never get heartbeats, and neither do
+/// roots below the level until something inside them is kept.
+///
+/// [`snapshot`]: ../struct.ConcatRecord.html#method.snapshot
+/// [`Handle::flush_due`]: ../struct.Handle.html#method.flush_due
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Heartbeat {
+    interval: Option<Duration>,
+    events: Option<usize>,
 }
 
+/// Receives the records of root spans as they close.
+pub(crate) type Flush = Box<dyn Fn(ConcatRecord) + Send + Sync>;
+
+/// Where a captured root span's record is put when it closes, instead of
+/// being flushed.
+pub(crate) type Captured = Arc<Mutex<Option<ConcatRecord>>>;
+
 #[derive(Debug)]
 pub(crate) struct Data {
     parent: Option<Id>,
     metadata: &'static Metadata<'static>,
     ref_count: AtomicUsize,
-    is_empty: bool,
+    span_id: SpanId,
+    // The trace this span belongs to, if it is a root span.
+    trace: Option<Trace>,
+    start: SystemTime,
+    started: Instant,
+    captured: Option<Captured>,
+    // Whether this span is a descendant of a captured root span, in which
+    // case its record is always kept in the span tree, so that it ends up in
+    // the capture.
+    in_capture: bool,
+    // The level set by the root span's level field, shared by the whole span
+    // tree.
+    level: Arc<LevelOverride>,
+    // How many heartbeat records have been flushed for this span, and when
+    // the last was.
+    snapshots: u64,
+    last_snapshot: Instant,
 }
 
-#[derive(Debug)]
-struct Slab {
-    slab: Vec<RwLock<Slot>>,
-}
+/// The severity of the least severe spans and events recorded in a span
+/// tree, if its root span overrides the configured level.
+#[derive(Debug, Default)]
+struct LevelOverride(AtomicUsize);
 
 #[derive(Debug)]
 struct Slot {
-    fields: String,
+    fields: Fields,
+    events: Vec<BufferedEvent>,
+    // When deduplicating events, maps the dedup keys of this span's events to
+    // their indices in `events`.
+    event_keys: HashMap<u64, usize>,
+    // The values of propagated fields inherited from this span's ancestors.
+    inherited: Fields,
+    // The records of this span's children which have already closed.
+    children: Vec<SpanRecord>,
     span: State,
+    // Incremented every time the slot is emptied, so that an ID handed out
+    // for a previous occupant of this slot is never mistaken for the current
+    // one.
+    generation: u32,
+    // The metadata of the span which last occupied this slot, to name it if
+    // an ID issued for it is misused after it closed.
+    closed: Option<&'static Metadata<'static>>,
 }
 
 #[derive(Debug)]
 enum State {
     Full(Data),
-    Empty(usize),
+    Empty,
 }
 
 struct ContextId {
@@ -118,6 +254,11 @@ impl SpanStack {
         }
     }
 
+    #[inline]
+    fn top(&self) -> Option<&Id> {
+        self.stack.last().map(|context_id| &context_id.id)
+    }
+
     #[inline]
     fn current(&self) -> Option<&Id> {
         self.stack
@@ -144,70 +285,49 @@ macro_rules! debug_panic {
 
 // ===== impl Span =====
 
-impl<'a> Span<'a> {
+impl Span {
     pub fn name(&self) -> &'static str {
-        match self.lock.span {
-            State::Full(ref data) => data.metadata.name(),
-            State::Empty(_) => unreachable!(),
-        }
+        self.metadata.name()
     }
 
     pub fn metadata(&self) -> &'static Metadata<'static> {
-        match self.lock.span {
-            State::Full(ref data) => data.metadata,
-            State::Empty(_) => unreachable!(),
-        }
+        self.metadata
     }
 
-    pub fn fields(&self) -> &str {
-        self.lock.fields.as_ref()
+    pub fn fields(&self) -> &Fields {
+        &self.fields
     }
 
-    pub fn parent(&self) -> Option<&Id> {
-        match self.lock.span {
-            State::Full(ref data) => data.parent.as_ref(),
-            State::Empty(_) => unreachable!(),
-        }
+    /// Returns the events recorded inside this span so far, oldest first.
+    ///
+    /// This does not include events recorded inside the span's children.
+    pub fn events(&self) -> &[BufferedEvent] {
+        &self.events
     }
 
-    #[inline(always)]
-    fn with_parent<'store, F, E>(
-        self,
-        my_id: &Id,
-        last_id: Option<&Id>,
-        f: &mut F,
-        store: &'store Store,
-    ) -> Result<(), E>
-    where
-        F: FnMut(&Id, Span<'_>) -> Result<(), E>,
-    {
-        if let Some(parent_id) = self.parent() {
-            if Some(parent_id) != last_id {
-                if let Some(parent) = store.get(parent_id) {
-                    parent.with_parent(parent_id, Some(my_id), f, store)?;
-                } else {
-                    debug_panic!("missing span for {:?}; this is a bug", parent_id);
-                }
-            }
-        }
-        f(my_id, self)
+    /// Returns the records of this span's children which have already
+    /// closed, in the order they closed, including their events.
+    pub fn children(&self) -> &[SpanRecord] {
+        &self.children
     }
-}
 
-impl<'a> fmt::Debug for Span<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Span")
-            .field("name", &self.name())
-            .field("parent", &self.parent())
-            .field("metadata", self.metadata())
-            .field("fields", &self.fields())
-            .finish()
+    pub fn span_id(&self) -> SpanId {
+        self.span_id
+    }
+
+    /// Returns the ID of the trace this span belongs to.
+    pub fn trace_id(&self) -> Option<TraceId> {
+        self.trace_id
+    }
+
+    pub fn parent(&self) -> Option<&Id> {
+        self.parent.as_ref()
     }
 }
 
 // ===== impl Context =====
 
-impl<'a, F> Context<'a, F> {
+impl<'a> Context<'a> {
     /// Applies a function to each span in the current trace context.
     ///
     /// The function is applied in order, beginning with the root of the trace,
@@ -216,93 +336,110 @@ impl<'a, F> Context<'a, F> {
     ///
     /// If invoked from outside of a span, the function will not be applied.
     ///
-    /// Note that if we are currently unwinding, this will do nothing, rather
-    /// than potentially causing a double panic.
+    /// Each span is copied before the function is called, and no lock is
+    /// held while it runs, so it may do anything, including logging.
     pub fn visit_spans<N, E>(&self, mut f: N) -> Result<(), E>
     where
-        N: FnMut(&Id, Span<'_>) -> Result<(), E>,
+        N: FnMut(&Id, Span) -> Result<(), E>,
     {
-        CONTEXT
-            .try_with(|current| {
-                if let Some(id) = current.borrow().current() {
-                    if let Some(span) = self.store.get(id) {
-                        // with_parent uses the call stack to visit the span
-                        // stack in reverse order, without having to allocate
-                        // a buffer.
-                        return span.with_parent(id, None, &mut f, self.store);
-                    } else {
-                        debug_panic!("missing span for {:?}; this is a bug", id);
-                    }
+        let current = match self.store.current() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        for id in self.store.ancestors(current) {
+            match self.store.get(&id) {
+                Some(span) => f(&id, span)?,
+                None => {
+                    debug_panic!("missing span for {:?}; this is a bug", id);
                 }
-                Ok(())
-            })
-            .unwrap_or(Ok(()))
+            }
+        }
+        Ok(())
     }
 
-    /// Executes a closure with the reference to the current span.
+    /// Executes a closure with a copy of the current span.
     pub fn with_current<N, R>(&self, f: N) -> Option<R>
     where
-        N: FnOnce((&Id, Span<'_>)) -> R,
+        N: FnOnce((&Id, Span)) -> R,
     {
-        // If the lock is poisoned or the thread local has already been
-        // destroyed, we might be in the middle of unwinding, so this
-        // will just do nothing rather than cause a double panic.
-        CONTEXT
-            .try_with(|current| {
-                if let Some(id) = current.borrow().current() {
-                    if let Some(span) = self.store.get(&id) {
-                        return Some(f((&id, span)));
-                    } else {
-                        debug_panic!("missing span for {:?}, this is a bug", id);
-                    }
-                }
+        let id = self.store.current()?;
+        match self.store.get(&id) {
+            Some(span) => Some(f((&id, span))),
+            None => {
+                debug_panic!("missing span for {:?}, this is a bug", id);
                 None
-            })
-            .ok()?
+            }
+        }
     }
 
-    pub(crate) fn new(store: &'a Store, fmt_fields: &'a F) -> Self {
-        Self { store, fmt_fields }
+    /// Returns the ID of the trace that the current span belongs to.
+    pub fn trace_id(&self) -> Option<TraceId> {
+        let current = self.store.current()?;
+        // The first ancestor is the root of the current trace.
+        let root = self.store.ancestors(current).into_iter().next()?;
+        match self.store.read_slot(&root)?.span {
+            State::Full(ref data) => data.trace.map(|trace| trace.id),
+            State::Empty => None,
+        }
     }
-}
 
-impl<'ctx, 'writer, F> FormatFields<'writer> for Context<'ctx, F>
-where
-    F: FormatFields<'writer>,
-{
-    #[inline]
-    fn format_fields<R>(&self, writer: &'writer mut dyn fmt::Write, fields: R) -> fmt::Result
-    where
-        R: RecordFields,
-    {
-        self.fmt_fields.format_fields(writer, fields)
+    pub(crate) fn new(store: &'a Store) -> Self {
+        Self { store }
     }
 }
 
+// Span IDs pack the slab index of the span's slot into the lower
+// `INDEX_BITS` bits, and the generation of that slot into the upper bits. The
+// index is offset by one, since span IDs may not be zero.
+const INDEX_BITS: u32 = 32;
+const INDEX_MASK: u64 = (1 << INDEX_BITS) - 1;
+
 #[inline]
-fn idx_to_id(idx: usize) -> Id {
-    Id::from_u64(idx as u64 + 1)
+fn idx_to_id(idx: usize, generation: u32) -> Id {
+    Id::from_u64((u64::from(generation) << INDEX_BITS) | (idx as u64 + 1))
 }
 
 #[inline]
 fn id_to_idx(id: &Id) -> usize {
-    id.into_u64() as usize - 1
+    (id.into_u64() & INDEX_MASK) as usize - 1
+}
+
+#[inline]
+fn id_to_generation(id: &Id) -> u32 {
+    (id.into_u64() >> INDEX_BITS) as u32
+}
+
+/// Returns the ID given to spans created while the slab is full.
+///
+/// Its index is beyond any slot the slab can hold, so nothing is ever recorded
+/// for it, as if the span were disabled.
+#[inline]
+fn disabled_id() -> Id {
+    Id::from_u64(u64::MAX)
 }
 
 impl Store {
-    pub(crate) fn with_capacity(capacity: usize) -> Self {
+    pub(crate) fn new(flush: Flush, config: Config) -> Self {
         Store {
-            inner: RwLock::new(Slab {
-                slab: Vec::with_capacity(capacity),
-            }),
-            next: AtomicUsize::new(0),
+            inner: Slab::new(),
+            poison_recoveries: AtomicUsize::new(0),
+            misuses: AtomicUsize::new(0),
+            records_sampled_out: AtomicUsize::new(0),
+            records_rate_limited: AtomicUsize::new(0),
+            events_outside_spans: AtomicUsize::new(0),
+            events_filtered: AtomicUsize::new(0),
+            events_sampled_out: AtomicUsize::new(0),
+            flush,
+            config,
         }
     }
 
     #[inline]
     pub(crate) fn current(&self) -> Option<Id> {
+        // The stack is already borrowed if the thread panicked while pushing
+        // or popping a span, and this is called from the panic hook.
         CONTEXT
-            .try_with(|current| current.borrow().current().cloned())
+            .try_with(|current| current.try_borrow().ok()?.current().cloned())
             .ok()?
     }
 
@@ -311,127 +448,480 @@ impl Store {
     }
 
     pub(crate) fn pop(&self, expected_id: &Id) {
-        let id = CONTEXT
-            .try_with(|current| current.borrow_mut().pop(expected_id))
-            .ok()
-            .and_then(|i| i);
-        if let Some(id) = id {
-            let _ = self.drop_span(id);
+        let popped = CONTEXT.try_with(|current| {
+            let mut current = current.borrow_mut();
+            match current.pop(expected_id) {
+                Some(id) => Ok(id),
+                None => Err(current.top().cloned()),
+            }
+        });
+        match popped {
+            Ok(Ok(id)) => {
+                let _ = self.drop_span(id);
+            }
+            Ok(Err(top)) => {
+                let mut fields = self.misused_span("span", "callsite", expected_id);
+                if let Some(top) = top {
+                    fields.extend(self.misused_span("current", "current_callsite", &top));
+                }
+                self.misuse("exit_not_current", fields);
+            }
+            // The thread is exiting.
+            Err(_) => {}
         }
     }
 
     /// Inserts a new span with the given data and fields into the slab,
     /// returning an ID for that span.
     ///
+    /// If the span has an explicitly-specified parent, `parent` is that
+    /// parent's ID in this store. Otherwise, it is ignored.
+    ///
     /// If there are empty slots in the slab previously allocated for spans
-    /// which have since been closed, the allocation and span ID of the most
-    /// recently emptied span will be reused. Otherwise, a new allocation will
-    /// be added to the slab.
+    /// which have since been closed, the allocation of the most recently
+    /// emptied span will be reused, with a new generation. Otherwise, a new
+    /// slot will be allocated, adding a page to the slab if necessary. If the
+    /// slab is full, the span is treated as disabled, and nothing is recorded
+    /// for it.
     #[inline]
-    pub(crate) fn new_span<F>(&self, attrs: &Attributes<'_>, fmt_fields: &F) -> Id
-    where
-        F: for<'writer> FormatFields<'writer>,
-    {
-        let mut span = Some(Data::new(attrs, self));
-
-        // The slab's free list is a modification of Treiber's lock-free stack,
-        // using slab indices instead of pointers, and with a provision for
-        // growing the slab when needed.
-        //
-        // In order to insert a new span into the slab, we "pop" the next free
-        // index from the stack.
-        loop {
-            // Acquire a snapshot of the head of the free list.
-            let head = self.next.load(Ordering::Relaxed);
-
-            {
-                // Try to insert the span without modifying the overall
-                // structure of the stack.
-                let this = try_lock!(self.inner.read(), else return Id::from_u64(0xDEADFACE));
-
-                // Can we insert without reallocating?
-                if head < this.slab.len() {
-                    // If someone else is writing to the head slot, we need to
-                    // acquire a new snapshot!
-                    if let Ok(mut slot) = this.slab[head].try_write() {
-                        // Is the slot we locked actually empty? If not, fall
-                        // through and try to grow the slab.
-                        if let Some(next) = slot.next() {
-                            // Is our snapshot still valid?
-                            if self.next.compare_and_swap(head, next, Ordering::Release) == head {
-                                // We can finally fill the slot!
-                                slot.fill(span.take().unwrap(), attrs, fmt_fields);
-                                return idx_to_id(head);
-                            }
-                        }
+    pub(crate) fn new_span(&self, attrs: &Attributes<'_>, parent: Option<&Id>) -> Id {
+        let mut span = Data::new(attrs, parent, self);
+        let inherited = match span.parent {
+            Some(ref parent) => match self.read_slot(parent) {
+                Some(parent) => {
+                    span.trace = parent.child_trace();
+                    if let State::Full(ref parent) = parent.span {
+                        span.level = parent.level.clone();
+                        span.in_capture = parent.in_capture || parent.captured.is_some();
                     }
+                    parent.propagated(&self.config.propagated)
+                }
+                None => Fields::new(),
+            },
+            None => Fields::new(),
+        };
+        // Record the fields before locking the slot: recording calls into
+        // user code, which may panic, and we don't want that to poison the
+        // lock. Nothing inside a trace that was sampled out is recorded.
+        let fields = if span.is_sampled() {
+            self.config.recorder.fields(|fields| attrs.record(fields))
+        } else {
+            Fields::new()
+        };
+        if span.parent.is_none() {
+            let mut trace = Trace::new(&fields);
+            if let Some(rate) = self.config.sample_rate(attrs.metadata()) {
+                trace.sample(rate);
+            }
+            span.trace = Some(trace);
+            if let Some(level) = self.config.level_override(&fields) {
+                span.level.set(level);
+            }
+        }
+        let (idx, entry) = match self.inner.alloc() {
+            Some(alloc) => alloc,
+            None => {
+                debug_panic!("span slab is full; too many spans are open at once");
+                // The span will never close, so release its reference to its
+                // parent now, lest the parent never close either.
+                if let Some(parent) = span.parent.take() {
+                    self.drop_span(parent);
+                }
+                return disabled_id();
+            }
+        };
+
+        // Nobody else can pop this slot off the free list while we hold it,
+        // but a reader with a stale ID may still briefly hold a read lock.
+        let mut slot = self.write_lock(&entry.slot);
+        slot.fill(span, fields, inherited);
+        idx_to_id(idx, slot.generation)
+    }
+
+    /// Returns a copy of the span with the specified `id`, if one currently
+    /// exists.
+    pub(crate) fn get(&self, id: &Id) -> Option<Span> {
+        self.read_slot(id)?.to_span()
+    }
+
+    /// Returns the metadata of the span with the specified `id`, if one
+    /// currently exists.
+    #[inline]
+    pub(crate) fn metadata(&self, id: &Id) -> Option<&'static Metadata<'static>> {
+        match self.read_slot(id)?.span {
+            State::Full(ref data) => Some(data.metadata),
+            State::Empty => None,
+        }
+    }
+
+    /// Returns the IDs of the span with the given `id` and its ancestors,
+    /// beginning with the root of its span tree.
+    fn ancestors(&self, id: Id) -> Vec<Id> {
+        let mut ids = Vec::new();
+        let mut next = Some(id);
+        while let Some(id) = next {
+            next = self.read_slot(&id).and_then(|slot| slot.parent());
+            ids.push(id);
+        }
+        ids.reverse();
+        ids
+    }
 
-                    // Our snapshot got stale, try again!
-                    atomic::spin_loop_hint();
-                    continue;
+    /// Records that the span with the given `id` has the given `fields`.
+    #[inline]
+    pub(crate) fn record(&self, id: &Id, values: &Record<'_>) {
+        if self.config.is_sampling() && !self.is_sampled(id) {
+            return;
+        }
+        let fields = self.config.recorder.fields(|fields| values.record(fields));
+        let level = self.config.level_override(&fields);
+        if let Some(mut slot) = self.write_slot(id) {
+            if let (Some(level), State::Full(ref data)) = (level, &slot.span) {
+                if data.parent.is_none() {
+                    data.level.set(level);
                 }
             }
+            slot.record(fields);
+        }
+    }
+
+    /// Records that the current thread panicked: `fields` are recorded on
+    /// the root of the current span tree, and a panic event with the fields
+    /// `event` is buffered in the current span.
+    ///
+    /// Returns `false` if there is no current span.
+    pub(crate) fn record_panic(&self, fields: Fields, event: Fields) -> bool {
+        let current = match self.current() {
+            Some(current) => current,
+            None => return false,
+        };
+        let fields = self
+            .config
+            .recorder
+            .fields(|recorded| recorded.extend(fields));
+        let event = report::panic(
+            self.config
+                .recorder
+                .fields(|recorded| recorded.extend(event)),
+        );
+        // The panicking thread may hold a lock on any of these slots, e.g.
+        // if user code called while recording panicked, so rather than
+        // blocking on a slot that is locked, skip it.
+        let mut root = Some(current.clone());
+        while let Some(id) = root.take() {
+            let parent = match self.try_read_slot(&id) {
+                Some(slot) => slot.parent(),
+                None => break,
+            };
+            match parent {
+                Some(parent) => root = Some(parent),
+                None => {
+                    root = Some(id);
+                    break;
+                }
+            }
+        }
+        if let Some(mut root) = root.and_then(|root| self.try_write_slot(&root)) {
+            root.record(fields);
+        }
+        if let Some(mut slot) = self.try_write_slot(&current) {
+            slot.push_event(event, self.config.recorder.dedup);
+        }
+        true
+    }
 
-            // We need to grow the slab, and must acquire a write lock.
-            if let Ok(mut this) = self.inner.try_write() {
-                let len = this.slab.len();
+    /// Returns `true` if a span or event with the given metadata, created in
+    /// the current span, should be recorded.
+    ///
+    /// This is checked against the level set by the current span tree's root
+    /// span, if it has one, or else the configured level. Spans created
+    /// outside of any span are always recorded, since a root span may set
+    /// its own level.
+    pub(crate) fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let current = self.current();
+        if current.is_none() && metadata.is_span() {
+            return true;
+        }
+        let level = current.and_then(|id| self.read_slot(&id)?.level_override());
+        let enabled = self.config.enabled(metadata, level);
+        if !enabled && metadata.is_event() {
+            self.events_filtered.fetch_add(1, Ordering::Relaxed);
+        }
+        enabled
+    }
 
-                // Insert the span into a new slot.
-                let slot = Slot::new(span.take().unwrap(), attrs, fmt_fields);
-                this.slab.push(RwLock::new(slot));
-                // TODO: can we grow the slab in chunks to avoid having to
-                // realloc as often?
+    /// Buffers an event in the span it was recorded inside of, if any.
+    ///
+    /// If the event has an explicitly-specified parent, `parent` is that
+    /// parent's ID in this store. Otherwise, it is ignored.
+    pub(crate) fn push_event(&self, event: &Event<'_>, parent: Option<&Id>) {
+        let current;
+        let parent = if event.is_root() {
+            None
+        } else if event.is_contextual() {
+            current = self.current();
+            current.as_ref()
+        } else {
+            parent
+        };
 
-                // Update the head pointer and return.
-                self.next.store(len + 1, Ordering::Release);
-                return idx_to_id(len);
+        if let Some(parent) = parent {
+            if self.config.is_sampling() && !self.is_sampled(parent) {
+                self.events_sampled_out.fetch_add(1, Ordering::Relaxed);
+                return;
             }
+            // As with span fields, record the event before locking the slot.
+            let mut event = self.config.recorder.event(event);
+            let heartbeat = match self.write_slot(parent) {
+                Some(mut slot) => {
+                    // The event may have an explicit parent in a different
+                    // span tree than the one it was enabled for.
+                    if !self.config.enabled(event.metadata(), slot.level_override()) {
+                        drop(slot);
+                        self.events_filtered.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                    if !self.config.propagated.is_empty() {
+                        event.inherit(&slot.propagated(&self.config.propagated));
+                    }
+                    slot.push_event(event, self.config.recorder.dedup);
+                    slot.heartbeat(&self.config)
+                }
+                None => None,
+            };
+            // Flush the heartbeat once we've released our lock on the slot.
+            if let Some(heartbeat) = heartbeat {
+                (self.flush)(heartbeat);
+            }
+        } else {
+            self.events_outside_spans.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-            atomic::spin_loop_hint();
+    /// Arranges for the record of the root span with the given `id` to be put
+    /// in `captured` when the span closes, rather than being flushed.
+    ///
+    /// Returns `false` if the span does not exist or is not a root span.
+    pub(crate) fn capture(&self, id: &Id, captured: Captured) -> bool {
+        let mut slot = match self.write_slot(id) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        match slot.span {
+            State::Full(ref mut data) if data.parent.is_none() => {
+                data.captured = Some(captured);
+                // Captured records are always kept, whatever the sample rate.
+                if let Some(ref mut trace) = data.trace {
+                    trace.sample_rate = None;
+                    trace.sampled = true;
+                }
+                true
+            }
+            _ => false,
         }
     }
 
-    /// Returns a `Span` to the span with the specified `id`, if one
-    /// currently exists.
-    #[inline]
-    pub(crate) fn get(&self, id: &Id) -> Option<Span<'_>> {
-        let read = try_lock!(self.inner.read(), else return None);
-        let lock = OwningHandle::try_new(read, |slab| {
-            unsafe { &*slab }.read_slot(id_to_idx(id)).ok_or(())
-        })
-        .ok()?;
-        Some(Span { lock })
+    /// Returns a record of everything the root span with the given `id` has
+    /// recorded so far, without closing it.
+    ///
+    /// Only children which have already closed are included. Returns `None`
+    /// if the span does not exist or is not a root span.
+    pub(crate) fn snapshot(&self, id: &Id) -> Option<ConcatRecord> {
+        let slot = self.read_slot(id)?;
+        let data = match slot.span {
+            State::Full(ref data) if data.parent.is_none() => data,
+            _ => return None,
+        };
+        Some(ConcatRecord::incomplete(slot.snapshot()?, data.trace?))
     }
 
-    /// Records that the span with the given `id` has the given `fields`.
-    #[inline]
-    pub(crate) fn record<F>(&self, id: &Id, fields: &Record<'_>, fmt_fields: &F)
-    where
-        F: for<'writer> FormatFields<'writer>,
-    {
-        let slab = try_lock!(self.inner.read(), else return);
-        let slot = slab.write_slot(id_to_idx(id));
-        if let Some(mut slot) = slot {
-            slot.record(fields, fmt_fields);
+    /// Flushes a record of everything recorded so far by each span which is
+    /// still open, marked as incomplete, e.g. when the process is exiting.
+    ///
+    /// Unless each span is flushed separately, one record is flushed for
+    /// each open root span, including its open descendants as well as the
+    /// closed ones. The spans are left open. Spans which are being captured
+    /// are skipped, since their records go to the capture.
+    pub(crate) fn flush_open(&self) {
+        struct Open {
+            id: Id,
+            parent: Option<Id>,
+            trace: Option<Trace>,
+            record: Option<SpanRecord>,
+            children: Vec<usize>,
+        }
+
+        let mut open = Vec::new();
+        for (idx, entry) in self.inner.iter() {
+            let slot = self.read_lock(&entry.slot);
+            let data = match slot.span {
+                State::Full(ref data)
+                    if data.is_sampled() && data.captured.is_none() && !data.in_capture =>
+                {
+                    data
+                }
+                _ => continue,
+            };
+            open.push(Open {
+                id: idx_to_id(idx, slot.generation),
+                parent: data.parent.clone(),
+                trace: data.trace,
+                record: slot.snapshot(),
+                children: Vec::new(),
+            });
         }
+
+        if self.config.flush_mode == FlushMode::EachSpan {
+            for span in open {
+                if let Some(record) = span.record {
+                    let trace = span.trace.unwrap_or_else(Trace::random);
+                    (self.flush)(ConcatRecord::incomplete(record, trace));
+                }
+            }
+            return;
+        }
+
+        let idxs = open
+            .iter()
+            .enumerate()
+            .map(|(i, span)| (span.id.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let mut roots = Vec::new();
+        for i in 0..open.len() {
+            let parent = open[i].parent.as_ref().and_then(|parent| idxs.get(parent));
+            match parent {
+                Some(&parent) => open[parent].children.push(i),
+                None => roots.push(i),
+            }
+        }
+
+        fn assemble(open: &mut [Open], i: usize) -> Option<SpanRecord> {
+            let mut record = open[i].record.take()?;
+            for child in mem::take(&mut open[i].children) {
+                if let Some(child) = assemble(open, child) {
+                    record.push_child(child);
+                }
+            }
+            Some(record)
+        }
+        for root in roots {
+            let trace = open[root].trace.unwrap_or_else(Trace::random);
+            if let Some(record) = assemble(&mut open, root) {
+                (self.flush)(ConcatRecord::incomplete(record, trace));
+            }
+        }
+    }
+
+    /// Flushes a warning record listing the spans which have been open for
+    /// longer than `older_than`, and returns how many there are.
+    ///
+    /// Each span is listed as a child of the record's root, with its fields,
+    /// a `parents` field naming its ancestors, a `callsite` field giving the
+    /// file and line it was created on, and how long it has been open. No
+    /// record is flushed if there are no such spans.
+    pub(crate) fn report_leaks(&self, older_than: Duration) -> usize {
+        struct Leaked {
+            metadata: &'static Metadata<'static>,
+            span_id: SpanId,
+            parent: Option<Id>,
+            fields: Fields,
+            start: SystemTime,
+            age: Duration,
+        }
+
+        // The names and parents of every open span, to find the ancestors of
+        // the leaked ones.
+        let mut open = HashMap::new();
+        let mut leaked = Vec::new();
+        for (idx, entry) in self.inner.iter() {
+            let slot = self.read_lock(&entry.slot);
+            let data = match slot.span {
+                State::Full(ref data) => data,
+                State::Empty => continue,
+            };
+            let id = idx_to_id(idx, slot.generation);
+            open.insert(id, (data.metadata.name(), data.parent.clone()));
+            let age = data.started.elapsed();
+            if age >= older_than {
+                let mut fields = slot.inherited.clone();
+                fields.extend(slot.fields.clone());
+                leaked.push(Leaked {
+                    metadata: data.metadata,
+                    span_id: data.span_id,
+                    parent: data.parent.clone(),
+                    fields,
+                    start: data.start,
+                    age,
+                });
+            }
+        }
+        if leaked.is_empty() {
+            return 0;
+        }
+
+        leaked.sort_by_key(|span| std::cmp::Reverse(span.age));
+        let spans = leaked
+            .into_iter()
+            .map(|span| {
+                let mut parents = Vec::new();
+                let mut parent = span.parent;
+                // Bound the walk, in case the parents changed while we were
+                // collecting them.
+                while let Some((name, next)) = parent.and_then(|id| open.get(&id)) {
+                    if parents.len() >= open.len() {
+                        break;
+                    }
+                    parents.push(*name);
+                    parent = next.clone();
+                }
+                parents.reverse();
+
+                let mut fields = span.fields;
+                fields.insert("parents", Value::Str(parents.join(" > ")));
+                fields.insert("callsite", Value::Str(report::callsite(span.metadata)));
+                SpanRecord::new(
+                    span.span_id,
+                    span.metadata,
+                    fields,
+                    Vec::new(),
+                    Vec::new(),
+                    span.start,
+                    span.age,
+                )
+            })
+            .collect::<Vec<_>>();
+        let count = spans.len();
+        (self.flush)(report::leaked_spans(older_than, spans));
+        count
     }
 
     /// Decrements the reference count of the span with the given `id`, and
     /// removes the span if it is zero.
     ///
-    /// The allocated span slot will be reused when a new span is created.
+    /// When a span is removed, its record is added to its parent's, or, if it
+    /// is a root span, flushed. The allocated span slot will be reused when a
+    /// new span is created.
     pub(crate) fn drop_span(&self, id: Id) -> bool {
-        let this = try_lock!(self.inner.read(), else return false);
+        if id == disabled_id() {
+            return false;
+        }
         let idx = id_to_idx(&id);
 
-        if !this
-            .slab
+        if !self
+            .inner
             .get(idx)
-            .and_then(|lock| {
-                let span = try_lock!(lock.read(), else return None);
+            .and_then(|entry| {
+                let span = self.read_lock(&entry.slot);
+                if span.generation != id_to_generation(&id) {
+                    // The span was already closed and its slot has been
+                    // reused; this ID no longer refers to anything.
+                    return None;
+                }
                 Some(span.drop_ref())
             })
             .unwrap_or_else(|| {
+                let fields = self.misused_span("span", "callsite", &id);
+                self.misuse("drop_closed", fields);
                 debug_panic!("tried to drop {:?} but it no longer exists!", id);
                 false
             })
@@ -441,19 +931,70 @@ impl Store {
 
         // Synchronize only if we are actually removing the span (stolen
         // from std::Arc);
-        atomic::fence(Ordering::Acquire);
-
-        this.remove(&self.next, idx);
+        sync::fence(Ordering::Acquire);
+
+        if let Some((mut data, record)) = self.remove(idx) {
+            let trace = data.trace.unwrap_or_else(Trace::random);
+            // Nothing is flushed for traces that were sampled out.
+            match data.parent.take() {
+                Some(parent) => {
+                    if self.config.flush_mode == FlushMode::EachSpan && !data.in_capture {
+                        if trace.sampled {
+                            (self.flush)(ConcatRecord::new(record, trace));
+                        } else {
+                            self.records_sampled_out.fetch_add(1, Ordering::Relaxed);
+                        }
+                    } else {
+                        let heartbeat = match self.write_slot(&parent) {
+                            Some(mut slot) => {
+                                slot.children.push(record);
+                                slot.heartbeat(&self.config)
+                            }
+                            None => None,
+                        };
+                        if let Some(heartbeat) = heartbeat {
+                            (self.flush)(heartbeat);
+                        }
+                    }
+                    // Release the closed span's reference to its parent,
+                    // which may in turn close the parent.
+                    self.drop_span(parent);
+                }
+                None if trace.sampled => {
+                    // Root spans below the level are recorded anyway, in case
+                    // they lower it, but only flushed if anything inside them
+                    // was kept.
+                    let filtered = !self.config.enabled(data.metadata, data.level.get());
+                    if filtered
+                        && data.captured.is_none()
+                        && record.events().is_empty()
+                        && record.children().is_empty()
+                    {
+                        return true;
+                    }
+                    let mut record = ConcatRecord::new(record, trace);
+                    if data.snapshots > 0 {
+                        record = record.with_snapshot(data.snapshots + 1);
+                    }
+                    self.flush(data.captured.take(), record)
+                }
+                None => {
+                    self.records_sampled_out.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
         true
     }
 
     pub(crate) fn clone_span(&self, id: &Id) -> Id {
-        let this = try_lock!(self.inner.read(), else return id.clone());
-        let idx = id_to_idx(id);
-
-        if let Some(span) = this.slab.get(idx).and_then(|span| span.read().ok()) {
+        if *id == disabled_id() {
+            return id.clone();
+        }
+        if let Some(span) = self.read_slot(id) {
             span.clone_ref();
         } else {
+            let fields = self.misused_span("span", "callsite", id);
+            self.misuse("clone_closed", fields);
             debug_panic!(
                 "tried to clone {:?}, but no span exists with that ID. this is a bug!",
                 id
@@ -461,100 +1002,664 @@ impl Store {
         }
         id.clone()
     }
+
+    /// Counts a misuse of a span, and in strict mode, flushes a record
+    /// reporting it, with the given kind and fields.
+    #[cold]
+    fn misuse(&self, kind: &'static str, fields: Fields) {
+        self.misuses.fetch_add(1, Ordering::Relaxed);
+        if self.config.strict {
+            let mut report = Fields::new();
+            report.insert("kind", Value::Str(kind.to_owned()));
+            report.extend(fields);
+            (self.flush)(report::span_misuse(report));
+        }
+    }
+
+    /// Returns fields describing a misused span, with the given names for
+    /// its name and callsite.
+    ///
+    /// The span is described if it is still open, or if it has closed and
+    /// was the last span to occupy its slot. Otherwise, the fields are empty.
+    fn misused_span(&self, name: &'static str, callsite: &'static str, id: &Id) -> Fields {
+        let mut fields = Fields::new();
+        if !self.config.strict {
+            return fields;
+        }
+        let closed = || {
+            let slot = self.read_lock(&self.inner.get(id_to_idx(id))?.slot);
+            // The slot's generation is incremented when the span closes.
+            let previous = slot.generation == id_to_generation(id).wrapping_add(1);
+            slot.closed.filter(|_| previous)
+        };
+        if let Some(metadata) = self.metadata(id).or_else(closed) {
+            fields.insert(name, Value::Str(metadata.name().to_owned()));
+            fields.insert(callsite, Value::Str(report::callsite(metadata)));
+        }
+        fields
+    }
+
+    /// Returns a write guard for the slot referenced by `id`, if that slot is
+    /// still occupied by the span the ID was issued for.
+    #[inline]
+    fn write_slot(&self, id: &Id) -> Option<RwLockWriteGuard<'_, Slot>> {
+        self.inner
+            .get(id_to_idx(id))
+            .map(|entry| self.write_lock(&entry.slot))
+            .filter(|lock| lock.is_current(id))
+    }
+
+    /// Returns a read guard for the slot referenced by `id`, if that slot is
+    /// still occupied by the span the ID was issued for.
+    #[inline]
+    fn read_slot(&self, id: &Id) -> Option<RwLockReadGuard<'_, Slot>> {
+        self.inner
+            .get(id_to_idx(id))
+            .map(|entry| self.read_lock(&entry.slot))
+            .filter(|lock| lock.is_current(id))
+    }
+
+    /// Like [`read_slot`], but returns `None` rather than blocking if the
+    /// slot is write-locked.
+    ///
+    /// [`read_slot`]: #method.read_slot
+    fn try_read_slot(&self, id: &Id) -> Option<RwLockReadGuard<'_, Slot>> {
+        let slot = &self.inner.get(id_to_idx(id))?.slot;
+        let lock = match slot.try_read() {
+            Ok(lock) => lock,
+            Err(TryLockError::WouldBlock) => return None,
+            Err(TryLockError::Poisoned(poisoned)) => {
+                drop(poisoned);
+                self.try_repair(slot);
+                slot.try_read().ok()?
+            }
+        };
+        Some(lock).filter(|lock| lock.is_current(id))
+    }
+
+    /// Like [`write_slot`], but returns `None` rather than blocking if the
+    /// slot is locked.
+    ///
+    /// [`write_slot`]: #method.write_slot
+    fn try_write_slot(&self, id: &Id) -> Option<RwLockWriteGuard<'_, Slot>> {
+        let slot = &self.inner.get(id_to_idx(id))?.slot;
+        let lock = match slot.try_write() {
+            Ok(lock) => lock,
+            Err(TryLockError::WouldBlock) => return None,
+            Err(TryLockError::Poisoned(poisoned)) => {
+                self.recovered(slot);
+                let mut guard = poisoned.into_inner();
+                guard.repair();
+                guard
+            }
+        };
+        Some(lock).filter(|lock| lock.is_current(id))
+    }
+
+    /// Returns `false` if the span with the given `id` belongs to a trace
+    /// that was sampled out.
+    fn is_sampled(&self, id: &Id) -> bool {
+        match self.read_slot(id) {
+            Some(slot) => match slot.span {
+                State::Full(ref data) => data.is_sampled(),
+                State::Empty => true,
+            },
+            None => true,
+        }
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns statistics about the spans in the store, and what it has
+    /// recorded.
+    ///
+    /// The statistics about open spans are gathered by visiting every slot,
+    /// so they may be slightly inconsistent if spans are opened or closed
+    /// meanwhile.
+    pub(crate) fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            lock_poison_recoveries: self.poison_recoveries.load(Ordering::Relaxed),
+            span_misuses: self.misuses.load(Ordering::Relaxed),
+            records_sampled_out: self.records_sampled_out.load(Ordering::Relaxed),
+            records_rate_limited: self.records_rate_limited.load(Ordering::Relaxed),
+            events_outside_spans: self.events_outside_spans.load(Ordering::Relaxed),
+            events_filtered: self.events_filtered.load(Ordering::Relaxed),
+            events_sampled_out: self.events_sampled_out.load(Ordering::Relaxed),
+            slab_capacity: self.inner.capacity(),
+            ..Stats::default()
+        };
+        let mut used = 0;
+        for (_, entry) in self.inner.iter() {
+            used += 1;
+            let slot = self.read_lock(&entry.slot);
+            if let State::Full(_) = slot.span {
+                let (events, bytes) = slot.buffered();
+                stats.live_spans += 1;
+                stats.buffered_events += events;
+                stats.buffered_bytes += bytes;
+            }
+        }
+        stats.free_slots = used - stats.live_spans;
+        stats
+    }
+
+    /// Acquires a read lock on a slot.
+    ///
+    /// If a thread panicked while holding the lock, the lock is recovered
+    /// rather than propagating the panic: the slot's data is still valid,
+    /// and a panic in one thread should not break tracing for the whole
+    /// process.
+    ///
+    /// Only a writer can repair a slot which was left half-cleared, so the
+    /// slot is write-locked and repaired first, rather than clearing the
+    /// poison flag from under a later writer.
+    #[inline]
+    fn read_lock<'a>(&self, slot: &'a RwLock<Slot>) -> RwLockReadGuard<'a, Slot> {
+        match slot.read() {
+            Ok(lock) => lock,
+            Err(poisoned) => {
+                drop(poisoned);
+                drop(self.write_lock(slot));
+                self.read_lock(slot)
+            }
+        }
+    }
+
+    /// Acquires a write lock on a slot, recovering it if it was poisoned.
+    #[inline]
+    fn write_lock<'a>(&self, slot: &'a RwLock<Slot>) -> RwLockWriteGuard<'a, Slot> {
+        slot.write().unwrap_or_else(|poisoned| {
+            self.recovered(slot);
+            let mut guard = poisoned.into_inner();
+            guard.repair();
+            guard
+        })
+    }
+
+    /// Flushes the record of a root span which has closed, unless it is
+    /// being captured or its rate limit suppresses it.
+    ///
+    /// Only these records count towards rate limits: heartbeats, incomplete
+    /// records and the records of child spans are always flushed.
+    fn flush(&self, captured: Option<Captured>, record: ConcatRecord) {
+        match captured {
+            // If whoever was capturing the span has given up waiting for it
+            // to close, flush the record as usual instead of dropping it.
+            Some(captured) if Arc::strong_count(&captured) > 1 => {
+                let mut captured = captured.lock().unwrap_or_else(|e| e.into_inner());
+                *captured = Some(record);
+            }
+            _ => {
+                self.flush_summaries(false);
+                if self.config.rate_limits.allow(record.root().name()) {
+                    (self.flush)(record);
+                } else {
+                    self.records_rate_limited.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Flushes the records which are due without anything else being
+    /// recorded: rate limit summaries, and the heartbeats of open root spans.
+    pub(crate) fn flush_due(&self) {
+        self.flush_summaries(false);
+        if self.config.heartbeats.is_empty() {
+            return;
+        }
+        for (_, entry) in self.inner.iter() {
+            // Only write-lock the slots of roots whose heartbeat is due,
+            // rather than blocking every span in the slab in turn.
+            if !self.read_lock(&entry.slot).heartbeat_due(&self.config) {
+                continue;
+            }
+            let heartbeat = self.write_lock(&entry.slot).heartbeat(&self.config);
+            if let Some(heartbeat) = heartbeat {
+                (self.flush)(heartbeat);
+            }
+        }
+    }
+
+    /// Flushes a `rate_limited` record for each rate limit which suppressed
+    /// records during its last period, if that period has elapsed.
+    ///
+    /// If `all` is `true`, summaries are flushed whether or not their period
+    /// has elapsed, e.g. because the process is exiting.
+    pub(crate) fn flush_summaries(&self, all: bool) {
+        for summary in self.config.rate_limits.summaries(all) {
+            (self.flush)(summary);
+        }
+    }
+
+    /// Recovers and repairs a poisoned slot, unless it is locked.
+    #[cold]
+    fn try_repair(&self, slot: &RwLock<Slot>) {
+        if let Err(TryLockError::Poisoned(poisoned)) = slot.try_write() {
+            self.recovered(slot);
+            poisoned.into_inner().repair();
+        }
+    }
+
+    #[cold]
+    fn recovered(&self, slot: &RwLock<Slot>) {
+        sync::clear_poison(slot);
+        self.poison_recoveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Remove a span slot from the slab, returning its slot to the free list,
+    /// along with the closed span's record.
+    fn remove(&self, idx: usize) -> Option<(Data, SpanRecord)> {
+        let closed = {
+            // Empty the data stored at that slot.
+            let mut slot = self.write_lock(&self.inner.get(idx)?.slot);
+            let data = match mem::replace(&mut slot.span, State::Empty) {
+                State::Full(data) => data,
+                // The slot has already been emptied; leave everything as it
+                // was and return `None`!
+                State::Empty => return None,
+            };
+
+            let mut fields = mem::take(&mut slot.inherited);
+            fields.extend(mem::take(&mut slot.fields));
+            let record = SpanRecord::new(
+                data.span_id,
+                data.metadata,
+                fields,
+                mem::take(&mut slot.events),
+                mem::take(&mut slot.children),
+                data.start,
+                data.started.elapsed(),
+            );
+            slot.event_keys.clear();
+            // Any IDs still referring to the previous occupant of this slot
+            // are now stale.
+            slot.generation = slot.generation.wrapping_add(1);
+            slot.closed = Some(data.metadata);
+            (data, record)
+        };
+
+        // Only make the slot available again once we've released our lock on
+        // it.
+        self.inner.release(idx);
+        Some(closed)
+    }
+}
+
+impl fmt::Debug for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Store")
+            .field("inner", &self.inner)
+            .field("poison_recoveries", &self.poison_recoveries)
+            .field("misuses", &self.misuses)
+            .field("config", &self.config)
+            .finish()
+    }
 }
 
 impl Data {
-    pub(crate) fn new(attrs: &Attributes<'_>, store: &Store) -> Self {
+    pub(crate) fn new(attrs: &Attributes<'_>, parent: Option<&Id>, store: &Store) -> Self {
         let parent = if attrs.is_root() {
             None
         } else if attrs.is_contextual() {
             store.current().as_ref().map(|id| store.clone_span(id))
         } else {
-            attrs.parent().map(|id| store.clone_span(id))
+            parent.map(|id| store.clone_span(id))
         };
         Self {
             metadata: attrs.metadata(),
             parent,
             ref_count: AtomicUsize::new(1),
-            is_empty: true,
+            span_id: SpanId::random(),
+            trace: None,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            captured: None,
+            in_capture: false,
+            level: Arc::default(),
+            snapshots: 0,
+            last_snapshot: Instant::now(),
+        }
+    }
+
+    fn is_sampled(&self) -> bool {
+        match self.trace {
+            Some(trace) => trace.sampled,
+            None => true,
         }
     }
 }
 
-impl Drop for Data {
-    fn drop(&mut self) {
-        // We have to actually unpack the option inside the `get_default`
-        // closure, since it is a `FnMut`, but testing that there _is_ a value
-        // here lets us avoid the thread-local access if we don't need the
-        // dispatcher at all.
-        if self.parent.is_some() {
-            dispatcher::get_default(|subscriber| {
-                if let Some(parent) = self.parent.take() {
-                    let _ = subscriber.try_close(parent);
-                }
-            })
+impl Config {
+    /// Returns `true` if a span or event with the given metadata is recorded
+    /// in a span tree with the given level override.
+    pub(crate) fn enabled(&self, metadata: &Metadata<'_>, level: Option<Level>) -> bool {
+        match level.or(self.max_level) {
+            Some(max) => severity(metadata.level()) >= severity(&max),
+            None => true,
+        }
+    }
+
+    /// Returns `true` if any root spans are sampled.
+    fn is_sampling(&self) -> bool {
+        self.sample_rate.is_some() || !self.span_sample_rates.is_empty()
+    }
+
+    /// Returns the rate at which root spans with the given metadata are
+    /// sampled, if they are.
+    fn sample_rate(&self, metadata: &Metadata<'_>) -> Option<f64> {
+        self.span_sample_rates
+            .iter()
+            .find(|(name, _)| name == metadata.name())
+            .map(|&(_, rate)| rate)
+            .or(self.sample_rate)
+    }
+
+    /// Returns when to flush heartbeat records for root spans with the given
+    /// metadata, if they have them.
+    fn heartbeat(&self, metadata: &Metadata<'_>) -> Option<Heartbeat> {
+        self.heartbeats
+            .iter()
+            .find(|(name, _)| name == metadata.name())
+            .map(|&(_, heartbeat)| heartbeat)
+    }
+
+    /// Returns the level set by the level field among `fields`, if there is
+    /// one, and its value is a level name such as `"debug"`.
+    fn level_override(&self, fields: &Fields) -> Option<Level> {
+        match fields.get(&self.level_field)? {
+            Value::Str(level) | Value::Debug(level) => level.trim().parse().ok(),
+            _ => None,
         }
     }
 }
 
-impl Slot {
-    fn new<F>(mut data: Data, attrs: &Attributes<'_>, fmt_fields: &F) -> Self
-    where
-        F: for<'writer> FormatFields<'writer>,
-    {
-        let mut fields = String::new();
-        fmt_fields
-            .format_fields(&mut fields, attrs)
-            .expect("formatting to string should not fail");
-        if fields.is_empty() {
-            data.is_empty = false;
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flushes a heartbeat record when something is recorded once
+    /// `interval` has passed since the previous heartbeat, or since the span
+    /// was created.
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Self {
+            interval: Some(interval),
+            ..self
         }
+    }
+
+    /// Flushes a heartbeat record once `events` events have been buffered in
+    /// the root span since the previous heartbeat.
+    ///
+    /// Only events recorded directly inside the root span are counted.
+    pub fn with_max_events(self, events: usize) -> Self {
         Self {
+            events: Some(events),
+            ..self
+        }
+    }
+
+    fn is_due(&self, last: Instant, events: usize) -> bool {
+        let interval = match self.interval {
+            Some(interval) => last.elapsed() >= interval,
+            None => false,
+        };
+        let events = match self.events {
+            Some(max) => events >= max,
+            None => false,
+        };
+        interval || events
+    }
+}
+
+impl LevelOverride {
+    fn get(&self) -> Option<Level> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            1 => Some(Level::TRACE),
+            2 => Some(Level::DEBUG),
+            3 => Some(Level::INFO),
+            4 => Some(Level::WARN),
+            _ => Some(Level::ERROR),
+        }
+    }
+
+    fn set(&self, level: Level) {
+        let level = usize::from(severity(&level)) + 1;
+        self.0.store(level, Ordering::Relaxed);
+    }
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Self {
+            fields: Fields::new(),
+            events: Vec::new(),
+            event_keys: HashMap::new(),
+            inherited: Fields::new(),
+            children: Vec::new(),
+            span: State::Empty,
+            generation: 0,
+            closed: None,
+        }
+    }
+}
+
+impl Slot {
+    /// Returns a record of everything this span has recorded so far, if it
+    /// is occupied.
+    ///
+    /// Only children which have already closed are included.
+    fn snapshot(&self) -> Option<SpanRecord> {
+        let data = match self.span {
+            State::Full(ref data) => data,
+            State::Empty => return None,
+        };
+        let mut fields = self.inherited.clone();
+        fields.extend(self.fields.clone());
+        Some(SpanRecord::new(
+            data.span_id,
+            data.metadata,
             fields,
-            span: State::Full(data),
+            self.events.clone(),
+            self.children.clone(),
+            data.start,
+            data.started.elapsed(),
+        ))
+    }
+
+    /// Returns a copy of this slot's span, if it is occupied.
+    fn to_span(&self) -> Option<Span> {
+        match self.span {
+            State::Full(ref data) => Some(Span {
+                metadata: data.metadata,
+                span_id: data.span_id,
+                trace_id: data.trace.map(|trace| trace.id),
+                parent: data.parent.clone(),
+                fields: self.fields.clone(),
+                events: self.events.clone(),
+                children: self.children.clone(),
+            }),
+            State::Empty => None,
         }
     }
 
-    fn next(&self) -> Option<usize> {
+    /// Returns the ID of this slot's span's parent, if it has one.
+    fn parent(&self) -> Option<Id> {
         match self.span {
-            State::Empty(next) => Some(next),
-            _ => None,
+            State::Full(ref data) => data.parent.clone(),
+            State::Empty => None,
         }
     }
 
-    fn fill<F>(&mut self, mut data: Data, attrs: &Attributes<'_>, fmt_fields: &F) -> usize
-    where
-        F: for<'writer> FormatFields<'writer>,
-    {
-        let fields = &mut self.fields;
-        fmt_fields
-            .format_fields(fields, attrs)
-            .expect("formatting to string should not fail");
-        if fields.is_empty() {
-            data.is_empty = false;
+    /// Returns `true` if this is a root span whose heartbeat record is due.
+    fn heartbeat_due(&self, config: &Config) -> bool {
+        let data = match self.span {
+            State::Full(ref data) if data.parent.is_none() => data,
+            _ => return false,
+        };
+        let heartbeat = match config.heartbeat(data.metadata) {
+            Some(heartbeat) => heartbeat,
+            None => return false,
+        };
+        // Captured records are returned in one piece, and nothing is flushed
+        // for traces that were sampled out.
+        if data.captured.is_some() || !data.is_sampled() {
+            return false;
         }
-        match mem::replace(&mut self.span, State::Full(data)) {
-            State::Empty(next) => next,
-            State::Full(_) => unreachable!("tried to fill a full slot"),
+        // As when it closes, a root below the level is only flushed if
+        // anything inside it was kept.
+        let filtered = !config.enabled(data.metadata, data.level.get());
+        if filtered && self.events.is_empty() && self.children.is_empty() {
+            return false;
         }
+        heartbeat.is_due(data.last_snapshot, self.events.len())
     }
 
-    fn record<F>(&mut self, fields: &Record<'_>, fmt_fields: &F)
-    where
-        F: for<'writer> FormatFields<'writer>,
-    {
-        let state = &mut self.span;
-        let buf = &mut self.fields;
-        match state {
-            State::Empty(_) => return,
-            State::Full(ref mut data) => {
-                fmt_fields
-                    .format_fields(buf, fields)
-                    .expect("formatting to string should not fail");
-                if buf.is_empty() {
-                    data.is_empty = false;
+    /// Takes everything this span has recorded since its last heartbeat
+    /// record, returning it as a new heartbeat record, if one is due.
+    fn heartbeat(&mut self, config: &Config) -> Option<ConcatRecord> {
+        if !self.heartbeat_due(config) {
+            return None;
+        }
+        let data = match self.span {
+            State::Full(ref mut data) => data,
+            State::Empty => return None,
+        };
+        let trace = data.trace?;
+        data.snapshots += 1;
+        data.last_snapshot = Instant::now();
+
+        let mut fields = self.inherited.clone();
+        fields.extend(self.fields.clone());
+        self.event_keys.clear();
+        let record = SpanRecord::new(
+            data.span_id,
+            data.metadata,
+            fields,
+            mem::take(&mut self.events),
+            mem::take(&mut self.children),
+            data.start,
+            data.started.elapsed(),
+        );
+        Some(ConcatRecord::incomplete(record, trace).with_snapshot(data.snapshots))
+    }
+
+    /// Counts the events buffered in this slot, including those of its closed
+    /// children, and estimates how many bytes they take up when written.
+    fn buffered(&self) -> (usize, usize) {
+        let mut events = self.events.len();
+        let mut bytes = fields_cost(&self.inherited)
+            + fields_cost(&self.fields)
+            + self.events.iter().map(event_cost).sum::<usize>();
+        for child in &self.children {
+            let (child_events, child_bytes) = tree_size(child);
+            events += child_events;
+            bytes += child_bytes;
+        }
+        (events, bytes)
+    }
+
+    /// Returns `true` if this slot is occupied by the span that `id` was
+    /// issued for, rather than being empty or holding a later span.
+    #[inline]
+    fn is_current(&self, id: &Id) -> bool {
+        match self.span {
+            State::Full(_) => self.generation == id_to_generation(id),
+            State::Empty => false,
+        }
+    }
+
+    fn fill(&mut self, data: Data, fields: Fields, inherited: Fields) {
+        self.fields.extend(fields);
+        self.inherited = inherited;
+        if let State::Full(_) = mem::replace(&mut self.span, State::Full(data)) {
+            unreachable!("tried to fill a full slot")
+        }
+    }
+
+    fn record(&mut self, fields: Fields) {
+        if let State::Full(ref mut data) = self.span {
+            // A root span's `traceparent` may only be known after it was
+            // created.
+            if data.parent.is_none() {
+                if let Some(mut trace) = Trace::from_fields(&fields) {
+                    // Whether the trace is sampled was decided when the span
+                    // was created, and may already have been acted on.
+                    if let Some(old) = data.trace {
+                        trace.sample_rate = old.sample_rate;
+                        trace.sampled = old.sampled;
+                    }
+                    data.trace = Some(trace);
+                }
+            }
+            self.fields.extend(fields);
+        }
+    }
+
+    /// Returns the level set by the root of this span's tree, if any.
+    fn level_override(&self) -> Option<Level> {
+        match self.span {
+            State::Full(ref data) => data.level.get(),
+            State::Empty => None,
+        }
+    }
+
+    /// Returns the trace which this span's children belong to.
+    fn child_trace(&self) -> Option<Trace> {
+        match self.span {
+            State::Full(ref data) => data.trace.map(|trace| Trace {
+                parent: Some(data.span_id),
+                ..trace
+            }),
+            State::Empty => None,
+        }
+    }
+
+    /// Returns the values of the fields with the given names which this
+    /// span's descendants inherit: its own values of those fields, and
+    /// those it inherited itself.
+    fn propagated(&self, names: &[String]) -> Fields {
+        let mut propagated = self.inherited.clone();
+        for (name, value) in self.fields.iter() {
+            if names.iter().any(|propagated| propagated == name) {
+                propagated.insert(name, value.clone());
+            }
+        }
+        propagated
+    }
+
+    fn push_event(&mut self, event: BufferedEvent, dedup: Option<Dedup>) {
+        if let Some(dedup) = dedup {
+            let key = event.dedup_key(dedup);
+            if let Some(&idx) = self.event_keys.get(&key) {
+                if let Some(existing) = self.events.get_mut(idx) {
+                    if event.is_repeat_of(existing, dedup) {
+                        existing.repeat(event);
+                        return;
+                    }
                 }
             }
+            self.event_keys.insert(key, self.events.len());
+        }
+        self.events.push(event);
+    }
+
+    /// Restores the slot to a consistent state after a thread panicked while
+    /// holding its write lock.
+    ///
+    /// An occupied slot's data is kept as-is. An empty slot may have been
+    /// interrupted while it was being cleared, so finish clearing it, lest
+    /// stale fields or events leak into the next span to occupy it.
+    fn repair(&mut self) {
+        if let State::Empty = self.span {
+            self.fields.clear();
+            self.events.clear();
+            self.event_keys.clear();
+            self.inherited.clear();
+            self.children.clear();
         }
     }
 
@@ -573,7 +1678,7 @@ impl Slot {
                 );
                 refs == 1
             }
-            State::Empty(_) => false,
+            State::Empty => false,
         }
     }
 
@@ -583,59 +1688,9 @@ impl Slot {
                 let _refs = data.ref_count.fetch_add(1, Ordering::Release);
                 debug_assert!(_refs != 0, "tried to clone a span that already closed!");
             }
-            State::Empty(_) => {
+            State::Empty => {
                 unreachable!("tried to clone a ref to a span that no longer exists, this is a bug")
             }
         }
     }
 }
-
-impl Slab {
-    #[inline]
-    fn write_slot(&self, idx: usize) -> Option<RwLockWriteGuard<'_, Slot>> {
-        self.slab.get(idx).and_then(|slot| slot.write().ok())
-    }
-
-    #[inline]
-    fn read_slot(&self, idx: usize) -> Option<RwLockReadGuard<'_, Slot>> {
-        self.slab
-            .get(idx)
-            .and_then(|slot| slot.read().ok())
-            .and_then(|lock| match lock.span {
-                State::Empty(_) => None,
-                State::Full(_) => Some(lock),
-            })
-    }
-
-    /// Remove a span slot from the slab.
-    fn remove(&self, next: &AtomicUsize, idx: usize) -> Option<Data> {
-        // Again we are essentially implementing a variant of Treiber's stack
-        // algorithm to push the removed span's index into the free list.
-        loop {
-            // Get a snapshot of the current free-list head.
-            let head = next.load(Ordering::Relaxed);
-
-            // Empty the data stored at that slot.
-            let mut slot = try_lock!(self.slab[idx].write(), else return None);
-            let data = match mem::replace(&mut slot.span, State::Empty(head)) {
-                State::Full(data) => data,
-                state => {
-                    // The slot has already been emptied; leave
-                    // everything as it was and return `None`!
-                    slot.span = state;
-                    return None;
-                }
-            };
-
-            // Is our snapshot still valid?
-            if next.compare_and_swap(head, idx, Ordering::Release) == head {
-                // Empty the string but retain the allocated capacity
-                // for future spans.
-                slot.fields.clear();
-                return Some(data);
-            }
-
-            atomic::spin_loop_hint();
-        }
-    }
-}
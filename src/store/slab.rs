@@ -0,0 +1,561 @@
+use std::{fmt, marker::PhantomData, ptr};
+
+use crate::sync::{
+    spin_loop, thread_local, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering, RwLock,
+    UnsafeCell,
+};
+
+// Slab addresses are laid out as follows, from least to most significant bit:
+//
+// - `PAGE_BITS` bits for the offset of the slot within its page,
+// - `PAGE_INDEX_BITS` bits for the index of the page within its shard,
+// - `SHARD_BITS` bits for the index of the shard.
+//
+// The offset and page index together form the slot's "local" index within
+// its shard, which is what the shard's free lists store.
+#[cfg(not(loom))]
+const PAGE_BITS: usize = 6;
+#[cfg(not(loom))]
+const PAGE_INDEX_BITS: usize = 10;
+#[cfg(not(loom))]
+const SHARD_BITS: usize = 8;
+
+// When model checking, use a tiny slab, so that tests exercise allocating
+// pages and falling back to other shards without an excessive number of
+// modeled objects.
+#[cfg(loom)]
+const PAGE_BITS: usize = 1;
+#[cfg(loom)]
+const PAGE_INDEX_BITS: usize = 1;
+#[cfg(loom)]
+const SHARD_BITS: usize = 1;
+const LOCAL_BITS: usize = PAGE_BITS + PAGE_INDEX_BITS;
+
+pub const PAGE_SIZE: usize = 1 << PAGE_BITS;
+pub const MAX_PAGES: usize = 1 << PAGE_INDEX_BITS;
+pub const MAX_SHARDS: usize = 1 << SHARD_BITS;
+
+/// Marks the end of a free list.
+const NIL: u64 = u32::MAX as u64;
+
+/// A lock-free slab of slots, each holding a `T` behind its own lock.
+///
+/// The slab is divided into shards. Each running thread claims a shard
+/// index of its own, if there are any left, and prefers allocating from that
+/// shard, so that threads creating spans concurrently rarely contend on the
+/// same free list. Shards are made up of fixed-size pages which are
+/// allocated the first time they are needed and are never moved or freed
+/// until the slab itself is dropped, so a reference to a slot remains valid
+/// for as long as the slab is borrowed. Thus, there is no need for a lock over
+/// the slab as a whole, only for the individual slots.
+///
+/// Each shard has two free lists. Slots released by the thread which owns the
+/// shard go on its local free list, which no other thread ever touches, so
+/// pushing to it and popping from it takes no atomic read-modify-write
+/// operations. Slots released by any other thread, e.g. because a span was
+/// closed on a different thread from the one that created it, go on the
+/// shard's remote free list, a Treiber stack with a tagged head. When its
+/// local list runs out, the owner takes the whole remote list at once.
+/// Threads which don't own a shard, and threads whose own shard is full, pop
+/// slots off the remote lists of other shards instead.
+pub struct Slab<T> {
+    shards: Box<[AtomicPtr<Shard<T>>]>,
+    // The slab owns its shards, so it is only `Send` and `Sync` if they are.
+    _shards: PhantomData<Box<Shard<T>>>,
+}
+
+struct Shard<T> {
+    pages: Box<[AtomicPtr<Page<T>>]>,
+    _pages: PhantomData<Box<Page<T>>>,
+
+    // The local index of the first slot on this shard's local free list, or
+    // `NIL`. Only the thread which owns this shard's index may access it.
+    local: UnsafeCell<u64>,
+
+    // The head of this shard's remote free list. The upper 32 bits are a tag
+    // which is incremented on every update, so that a compare-and-swap
+    // against a stale snapshot of the head fails even if the same index has
+    // since been popped and pushed back (the ABA problem). The lower 32 bits
+    // are the local index of the first free slot, or `NIL`.
+    remote: AtomicU64,
+
+    // The local index of the next slot that has never been handed out.
+    len: AtomicUsize,
+}
+
+struct Page<T> {
+    slots: Box<[Entry<T>]>,
+}
+
+pub struct Entry<T> {
+    // The local index of the next free slot, while this slot is on one of
+    // its shard's free lists.
+    next: AtomicU64,
+    pub slot: RwLock<T>,
+}
+
+/// The shard which the current thread prefers to allocate from, and, if the
+/// thread owns that shard's index, its claim on it. Owning an index means
+/// owning the local free list of that index's shard in every slab.
+struct Home {
+    idx: usize,
+    claim: Option<Claim>,
+}
+
+/// A flag which is set while a shard index is owned by a running thread.
+#[cfg(not(loom))]
+type Claim = &'static AtomicBool;
+#[cfg(loom)]
+type Claim = loom::sync::Arc<AtomicBool>;
+
+thread_local! {
+    static HOME: Home = Home::claim();
+}
+
+// ===== impl Slab =====
+
+impl<T: Default> Slab<T> {
+    pub fn new() -> Self {
+        Slab {
+            shards: (0..MAX_SHARDS)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            _shards: PhantomData,
+        }
+    }
+
+    /// Returns the slot at the given address, if it has been allocated.
+    #[inline]
+    pub fn get(&self, idx: usize) -> Option<&Entry<T>> {
+        let shard = self.shard(idx >> LOCAL_BITS)?;
+        shard.entry(idx & ((1 << LOCAL_BITS) - 1))
+    }
+
+    /// Takes an empty slot off a free list, or allocates a new one, returning
+    /// its address.
+    ///
+    /// The calling thread has exclusive ownership of the returned slot until
+    /// it is passed back to `release`. Returns `None` if every shard is full.
+    pub fn alloc(&self) -> Option<(usize, &Entry<T>)> {
+        let (home, owned) = Home::current();
+        // Prefer this thread's own shard, falling back to the others only if
+        // it has run out of room entirely.
+        (0..MAX_SHARDS)
+            .map(|i| (home + i) % MAX_SHARDS)
+            .find_map(|shard_idx| {
+                let shard = self.shard_or_insert(shard_idx);
+                let local = shard.alloc(owned && shard_idx == home)?;
+                let entry = shard.entry(local)?;
+                Some(((shard_idx << LOCAL_BITS) | local, entry))
+            })
+    }
+
+    /// Returns an iterator over every slot which has ever been handed out,
+    /// whether or not it is currently occupied, along with its address.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Entry<T>)> + '_ {
+        (0..MAX_SHARDS)
+            .filter_map(move |shard_idx| Some((shard_idx, self.shard(shard_idx)?)))
+            .flat_map(|(shard_idx, shard)| {
+                let len = shard.len.load(Ordering::Relaxed);
+                (0..len.min(MAX_PAGES * PAGE_SIZE)).filter_map(move |local| {
+                    Some(((shard_idx << LOCAL_BITS) | local, shard.entry(local)?))
+                })
+            })
+    }
+
+    /// Returns the number of slots in the pages which have been allocated.
+    pub fn capacity(&self) -> usize {
+        (0..MAX_SHARDS)
+            .filter_map(|idx| self.shard(idx))
+            .map(|shard| {
+                let pages = shard
+                    .pages
+                    .iter()
+                    .filter(|page| !page.load(Ordering::Acquire).is_null())
+                    .count();
+                pages * PAGE_SIZE
+            })
+            .sum()
+    }
+
+    /// Pushes the slot at the given address onto one of its shard's free
+    /// lists: the local list if the calling thread owns the shard, or else
+    /// the remote list.
+    ///
+    /// The slot must already have been emptied.
+    pub fn release(&self, idx: usize) {
+        let shard_idx = idx >> LOCAL_BITS;
+        if let Some(shard) = self.shard(shard_idx) {
+            let (home, owned) = Home::current();
+            shard.release(idx & ((1 << LOCAL_BITS) - 1), owned && shard_idx == home);
+        }
+    }
+
+    #[inline]
+    fn shard(&self, idx: usize) -> Option<&Shard<T>> {
+        let ptr = self.shards.get(idx)?.load(Ordering::Acquire);
+        // Safety: shards are only ever freed when the slab is dropped, so if
+        // the pointer is non-null, it is valid for as long as `self` is.
+        unsafe { ptr.as_ref() }
+    }
+
+    fn shard_or_insert(&self, idx: usize) -> &Shard<T> {
+        if let Some(shard) = self.shard(idx) {
+            return shard;
+        }
+
+        let new = Box::into_raw(Box::new(Shard::new()));
+        match self.shards[idx].compare_exchange(
+            ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            // Safety: we just published this pointer, and it lives until the
+            // slab is dropped.
+            Ok(_) => unsafe { &*new },
+            Err(existing) => {
+                // Someone else allocated the shard first; use theirs.
+                //
+                // Safety: `new` was never shared, and `existing` is non-null
+                // and lives until the slab is dropped.
+                unsafe {
+                    drop(Box::from_raw(new));
+                    &*existing
+                }
+            }
+        }
+    }
+}
+
+impl<T: Default> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Slab<T> {
+    fn drop(&mut self) {
+        for shard in self.shards.iter() {
+            let ptr = shard.load(Ordering::Acquire);
+            if !ptr.is_null() {
+                // Safety: we have exclusive access to the slab, and every
+                // non-null shard pointer came from `Box::into_raw`.
+                drop(unsafe { Box::from_raw(ptr) });
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for Slab<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shards = self
+            .shards
+            .iter()
+            .filter(|shard| !shard.load(Ordering::Acquire).is_null())
+            .count();
+        f.debug_struct("Slab").field("shards", &shards).finish()
+    }
+}
+
+// ===== impl Shard =====
+
+impl<T: Default> Shard<T> {
+    fn new() -> Self {
+        Shard {
+            pages: (0..MAX_PAGES)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            _pages: PhantomData,
+            local: UnsafeCell::new(NIL),
+            remote: AtomicU64::new(NIL),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn entry(&self, local: usize) -> Option<&Entry<T>> {
+        let ptr = self.pages.get(local >> PAGE_BITS)?.load(Ordering::Acquire);
+        // Safety: pages are only ever freed when the shard is dropped.
+        let page = unsafe { ptr.as_ref() }?;
+        page.slots.get(local & (PAGE_SIZE - 1))
+    }
+
+    /// Takes a free slot, returning its local index.
+    ///
+    /// `owned` must only be `true` if the calling thread owns this shard.
+    fn alloc(&self, owned: bool) -> Option<usize> {
+        if owned {
+            if let Some(local) = self.pop_local() {
+                return Some(local);
+            }
+            // Take every slot that other threads have released since we
+            // last looked, and start over with those as our local list.
+            let head = self.take_remote();
+            if head != NIL {
+                // Safety: only the owner accesses the local list.
+                self.local.with_mut(|local| unsafe { *local = head });
+                return self.pop_local();
+            }
+        } else if let Some(local) = self.pop_remote() {
+            return Some(local);
+        }
+
+        // The free lists are empty, so hand out a slot that has never been
+        // used before, allocating the page it lives on if needed.
+        let local = self.len.fetch_add(1, Ordering::Relaxed);
+        if local >= MAX_PAGES * PAGE_SIZE {
+            // This shard is full. Undo the increment so that `len` can't
+            // eventually overflow if we keep trying.
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        self.page_or_insert(local >> PAGE_BITS);
+        Some(local)
+    }
+
+    /// Puts a slot back on one of the free lists.
+    ///
+    /// `owned` must only be `true` if the calling thread owns this shard.
+    fn release(&self, local: usize, owned: bool) {
+        let entry = match self.entry(local) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if owned {
+            // Safety: only the owner accesses the local list, and the entries
+            // on it.
+            self.local.with_mut(|head| unsafe {
+                entry.next.store(*head, Ordering::Relaxed);
+                *head = local as u64;
+            });
+            return;
+        }
+
+        let mut head = self.remote.load(Ordering::Relaxed);
+        loop {
+            entry.next.store(head & NIL, Ordering::Release);
+            let new_head = (tag(head).wrapping_add(1) << 32) | local as u64;
+            match self.remote.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => {
+                    head = actual;
+                    spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Pops a slot off the local free list. Must only be called by the
+    /// thread which owns this shard.
+    fn pop_local(&self) -> Option<usize> {
+        // Safety: only the owner accesses the local list, and the entries on
+        // it.
+        self.local.with_mut(|head| unsafe {
+            let local = *head;
+            if local == NIL {
+                return None;
+            }
+            *head = self.entry(local as usize)?.next.load(Ordering::Relaxed);
+            Some(local as usize)
+        })
+    }
+
+    /// Pops a single slot off the remote free list.
+    fn pop_remote(&self) -> Option<usize> {
+        let mut head = self.remote.load(Ordering::Acquire);
+        loop {
+            let local = head & NIL;
+            if local == NIL {
+                return None;
+            }
+
+            // The slot at the head of the list is guaranteed to have been
+            // allocated, since it was released at some point.
+            let next = self.entry(local as usize)?.next.load(Ordering::Acquire);
+            let new_head = (tag(head).wrapping_add(1) << 32) | next;
+            match self.remote.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(local as usize),
+                Err(actual) => {
+                    // Our snapshot got stale, try again!
+                    head = actual;
+                    spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Empties the remote free list, returning the local index of its first
+    /// slot, or `NIL`.
+    fn take_remote(&self) -> u64 {
+        let mut head = self.remote.load(Ordering::Acquire);
+        loop {
+            if head & NIL == NIL {
+                return NIL;
+            }
+            let new_head = (tag(head).wrapping_add(1) << 32) | NIL;
+            match self.remote.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return head & NIL,
+                Err(actual) => {
+                    head = actual;
+                    spin_loop();
+                }
+            }
+        }
+    }
+
+    fn page_or_insert(&self, idx: usize) {
+        let page = &self.pages[idx];
+        if !page.load(Ordering::Acquire).is_null() {
+            return;
+        }
+
+        let new = Box::into_raw(Box::new(Page::new()));
+        if page
+            .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // Another thread allocated the same page first.
+            //
+            // Safety: `new` was never shared.
+            drop(unsafe { Box::from_raw(new) });
+        }
+    }
+}
+
+// Safety: a shard's local free list is only ever accessed by the thread which
+// owns the shard's index, and everything else in it is either atomic or
+// behind a slot's lock.
+unsafe impl<T: Send + Sync> Sync for Shard<T> {}
+
+impl<T> Drop for Shard<T> {
+    fn drop(&mut self) {
+        for page in self.pages.iter() {
+            let ptr = page.load(Ordering::Acquire);
+            if !ptr.is_null() {
+                // Safety: we have exclusive access to the shard, and every
+                // non-null page pointer came from `Box::into_raw`.
+                drop(unsafe { Box::from_raw(ptr) });
+            }
+        }
+    }
+}
+
+// ===== impl Page =====
+
+impl<T: Default> Page<T> {
+    fn new() -> Self {
+        Page {
+            slots: (0..PAGE_SIZE)
+                .map(|_| Entry {
+                    next: AtomicU64::new(NIL),
+                    slot: RwLock::new(T::default()),
+                })
+                .collect(),
+        }
+    }
+}
+
+// ===== impl Home =====
+
+impl Home {
+    /// Returns the index of the current thread's shard, and whether the
+    /// thread owns it.
+    #[inline]
+    fn current() -> (usize, bool) {
+        // If the thread is exiting, it no longer owns a shard.
+        HOME.try_with(|home| (home.idx, home.claim.is_some()))
+            .unwrap_or((0, false))
+    }
+
+    /// Claims a shard index which no other running thread owns, if there
+    /// are any left.
+    fn claim() -> Self {
+        for idx in 0..MAX_SHARDS {
+            let claim = claim(idx);
+            let claimed = claim
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok();
+            if claimed {
+                return Home {
+                    idx,
+                    claim: Some(claim),
+                };
+            }
+        }
+        // Every index is owned, so spread the remaining threads out over
+        // the shards.
+        Home {
+            idx: unclaimed().fetch_add(1, Ordering::Relaxed) % MAX_SHARDS,
+            claim: None,
+        }
+    }
+}
+
+impl Drop for Home {
+    fn drop(&mut self) {
+        if let Some(claim) = &self.claim {
+            // Hand the local free lists of this index's shards over to the
+            // next thread to claim it.
+            claim.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Returns the claim on the shard index `idx`.
+#[cfg(not(loom))]
+fn claim(idx: usize) -> Claim {
+    static CLAIMS: [AtomicBool; MAX_SHARDS] = [const { AtomicBool::new(false) }; MAX_SHARDS];
+    &CLAIMS[idx]
+}
+
+/// Returns the number of threads which started while every shard index was
+/// owned.
+#[cfg(not(loom))]
+fn unclaimed() -> &'static AtomicUsize {
+    static UNCLAIMED: AtomicUsize = AtomicUsize::new(0);
+    &UNCLAIMED
+}
+
+#[cfg(loom)]
+fn unclaimed() -> &'static AtomicUsize {
+    loom::lazy_static! {
+        static ref UNCLAIMED: AtomicUsize = AtomicUsize::new(0);
+    }
+    &UNCLAIMED
+}
+
+/// Returns the claim on the shard index `idx`.
+///
+/// Model-checked statics are dropped before the main thread's locals, so
+/// each `Home` holds a reference to its own claim.
+#[cfg(loom)]
+fn claim(idx: usize) -> Claim {
+    loom::lazy_static! {
+        static ref CLAIMS: Vec<Claim> = (0..MAX_SHARDS)
+            .map(|_| Claim::new(AtomicBool::new(false)))
+            .collect();
+    }
+    CLAIMS[idx].clone()
+}
+
+#[inline]
+fn tag(head: u64) -> u64 {
+    head >> 32
+}
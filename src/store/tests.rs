@@ -0,0 +1,74 @@
+//! Tests for the span store.
+//!
+//! The tests in `unit` run on real threads, and under Miri with `cargo miri
+//! test`. The tests in `model` are model-checked with `loom`, and only run
+//! with `--cfg loom`.
+use super::*;
+use crate::record::Value;
+use tracing_core::{
+    callsite::{Callsite, Identifier},
+    field,
+    metadata::Kind,
+    subscriber::Interest,
+    Level,
+};
+
+#[cfg(loom)]
+mod model;
+#[cfg(not(loom))]
+mod unit;
+
+struct TestCallsite;
+static CALLSITE: TestCallsite = TestCallsite;
+static META: Metadata<'static> = Metadata::new(
+    "test_span",
+    "tracing_concat::store::tests",
+    Level::INFO,
+    None,
+    None,
+    None,
+    tracing_core::field::FieldSet::new(&["n"], Identifier(&CALLSITE)),
+    Kind::SPAN,
+);
+
+impl Callsite for TestCallsite {
+    fn set_interest(&self, _: Interest) {}
+    fn metadata(&self) -> &Metadata<'_> {
+        &META
+    }
+}
+
+/// Creates a new root span in `store` with its `n` field set to `n`.
+fn new_span(store: &Store, n: u64) -> Id {
+    let field = META.fields().field("n").unwrap();
+    let values = [(&field, Some(&n as &dyn field::Value))];
+    let values = META.fields().value_set(&values);
+    store.new_span(&Attributes::new_root(&META, &values), None)
+}
+
+/// Creates a new span in `store` with the given parent, and its `n` field set
+/// to `n`.
+fn new_child(store: &Store, parent: &Id, n: u64) -> Id {
+    let field = META.fields().field("n").unwrap();
+    let values = [(&field, Some(&n as &dyn field::Value))];
+    let values = META.fields().value_set(&values);
+    store.new_span(
+        &Attributes::child_of(parent.clone(), &META, &values),
+        Some(parent),
+    )
+}
+
+/// Records `n` as the value of the `n` field on the span with the given ID.
+fn record(store: &Store, id: &Id, n: u64) {
+    let field = META.fields().field("n").unwrap();
+    let values = [(&field, Some(&n as &dyn field::Value))];
+    let values = META.fields().value_set(&values);
+    store.record(id, &Record::new(&values))
+}
+
+/// Returns the value of the `n` field of the span with the given ID, if it is
+/// open.
+#[cfg(not(loom))]
+fn n(store: &Store, id: &Id) -> Option<Value> {
+    store.get(id)?.fields().get("n").cloned()
+}
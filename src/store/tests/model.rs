@@ -0,0 +1,181 @@
+//! Model-checked tests for the store's lock-free slab and reference counting.
+//!
+//! Run with:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib store::tests
+//! ```
+use super::*;
+use loom::{sync::Arc, thread};
+
+#[test]
+fn concurrent_new_span_ids_are_unique() {
+    loom::model(|| {
+        let store = Arc::new(Store::new(Box::new(|_| {}), Config::default()));
+        let threads = (0..2)
+            .map(|n| {
+                let store = store.clone();
+                thread::spawn(move || new_span(&store, n))
+            })
+            .collect::<Vec<_>>();
+        let main = new_span(&store, 2);
+
+        let ids = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .chain(Some(main))
+            .collect::<Vec<_>>();
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[i + 1..].contains(id), "{:?} was handed out twice", id);
+            let span = store.get(id).expect("span should exist");
+            assert_eq!(span.fields().get("n"), Some(&Value::U64(i as u64)));
+        }
+    });
+}
+
+#[test]
+fn span_is_not_freed_while_referenced() {
+    loom::model(|| {
+        let store = Arc::new(Store::new(Box::new(|_| {}), Config::default()));
+        let id = new_span(&store, 1);
+        let clone = store.clone_span(&id);
+
+        let thread = {
+            let store = store.clone();
+            let id = id.clone();
+            thread::spawn(move || {
+                // This thread still holds a reference, so the span must still
+                // exist no matter what the other thread does.
+                assert!(store.get(&id).is_some());
+                store.drop_span(id)
+            })
+        };
+
+        let closed_here = store.drop_span(clone);
+        let closed_there = thread.join().unwrap();
+        assert!(
+            closed_here ^ closed_there,
+            "span must be closed exactly once"
+        );
+        assert!(store.get(&id).is_none());
+    });
+}
+
+#[test]
+fn clone_races_with_drop() {
+    loom::model(|| {
+        let store = Arc::new(Store::new(Box::new(|_| {}), Config::default()));
+        let id = new_span(&store, 1);
+        let clone = store.clone_span(&id);
+
+        let thread = {
+            let store = store.clone();
+            let id = id.clone();
+            thread::spawn(move || {
+                let id = store.clone_span(&id);
+                assert!(!store.drop_span(id));
+            })
+        };
+
+        assert!(!store.drop_span(clone));
+        thread.join().unwrap();
+        assert!(store.get(&id).is_some());
+        assert!(store.drop_span(id));
+    });
+}
+
+#[test]
+fn reused_slots_do_not_alias() {
+    loom::model(|| {
+        let store = Arc::new(Store::new(Box::new(|_| {}), Config::default()));
+        let old = new_span(&store, 1);
+
+        let thread = {
+            let store = store.clone();
+            let old = old.clone();
+            thread::spawn(move || assert!(store.drop_span(old)))
+        };
+        let new = new_span(&store, 2);
+        thread.join().unwrap();
+
+        assert_ne!(old, new);
+        assert!(store.get(&old).is_none(), "stale ID must not resolve");
+        assert_eq!(
+            store.get(&new).expect("new span").fields().get("n"),
+            Some(&Value::U64(2))
+        );
+
+        // The slot freed by the other thread is reused by the next span, which
+        // must not be reachable through the old ID either.
+        let newer = new_span(&store, 3);
+        assert!(newer != old && newer != new);
+        assert!(store.get(&old).is_none(), "stale ID must not resolve");
+
+        // Recording into a stale ID must be ignored, rather than modifying
+        // whichever span now occupies the slot.
+        record(&store, &old, 4);
+        assert_eq!(
+            store.get(&new).expect("new span").fields().get("n"),
+            Some(&Value::U64(2))
+        );
+        assert_eq!(
+            store.get(&newer).expect("newer span").fields().get("n"),
+            Some(&Value::U64(3))
+        );
+    });
+}
+
+#[test]
+fn record_races_with_close() {
+    loom::model(|| {
+        let store = Arc::new(Store::new(Box::new(|_| {}), Config::default()));
+        let id = new_span(&store, 1);
+        let clone = store.clone_span(&id);
+
+        let thread = {
+            let store = store.clone();
+            let id = id.clone();
+            thread::spawn(move || {
+                record(&store, &id, 2);
+                let span = store.get(&id).expect("span should still exist");
+                assert_eq!(span.fields().get("n"), Some(&Value::U64(2)));
+                drop(span);
+                store.drop_span(id)
+            })
+        };
+
+        let closed_here = store.drop_span(clone);
+        let other = new_span(&store, 3);
+        let closed_there = thread.join().unwrap();
+        assert!(closed_here ^ closed_there);
+        assert_eq!(
+            store.get(&other).expect("span").fields().get("n"),
+            Some(&Value::U64(3))
+        );
+    });
+}
+
+#[test]
+fn parent_closes_when_slab_is_full() {
+    loom::model(|| {
+        let store = Store::new(Box::new(|_| {}), Config::default());
+        let parent = new_span(&store, 0);
+        let mut children = Vec::new();
+        loop {
+            let child = new_child(&store, &parent, 1);
+            if child == disabled_id() {
+                break;
+            }
+            children.push(child);
+        }
+        let capacity = slab::MAX_SHARDS * slab::MAX_PAGES * slab::PAGE_SIZE;
+        assert_eq!(children.len(), capacity - 1);
+
+        // The span which didn't fit must not keep its parent open.
+        for child in children {
+            assert!(store.drop_span(child));
+        }
+        assert!(store.drop_span(parent.clone()));
+        assert!(store.get(&parent).is_none());
+    });
+}
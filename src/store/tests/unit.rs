@@ -0,0 +1,260 @@
+use super::*;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Returns a store which collects the records it flushes.
+fn collecting() -> (Store, Arc<Mutex<Vec<ConcatRecord>>>) {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let flushed = records.clone();
+    let flush = Box::new(move |record| flushed.lock().unwrap().push(record));
+    (Store::new(flush, Config::default()), records)
+}
+
+#[test]
+fn new_span_get_and_record() {
+    let (store, _) = collecting();
+    let id = new_span(&store, 1);
+    assert_eq!(n(&store, &id), Some(Value::U64(1)));
+    assert_eq!(store.get(&id).expect("span").name(), "test_span");
+
+    record(&store, &id, 2);
+    assert_eq!(n(&store, &id), Some(Value::U64(2)));
+}
+
+#[test]
+fn span_closes_when_last_reference_is_dropped() {
+    let (store, records) = collecting();
+    let id = new_span(&store, 1);
+    let clone = store.clone_span(&id);
+
+    assert!(!store.drop_span(clone));
+    assert_eq!(n(&store, &id), Some(Value::U64(1)));
+    assert!(records.lock().unwrap().is_empty());
+
+    assert!(store.drop_span(id.clone()));
+    assert!(store.get(&id).is_none());
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].root().fields().get("n"), Some(&Value::U64(1)));
+}
+
+#[test]
+fn child_records_are_added_to_their_parent() {
+    let (store, records) = collecting();
+    let parent = new_span(&store, 1);
+    let child = new_child(&store, &parent, 2);
+
+    // The child holds a reference to its parent, so the parent stays open
+    // until the child closes.
+    assert!(!store.drop_span(parent.clone()));
+    assert!(store.get(&parent).is_some());
+    assert!(store.drop_span(child));
+    assert!(store.get(&parent).is_none());
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    let root = records[0].root();
+    assert_eq!(root.fields().get("n"), Some(&Value::U64(1)));
+    assert_eq!(root.children().len(), 1);
+    assert_eq!(root.children()[0].fields().get("n"), Some(&Value::U64(2)));
+}
+
+#[test]
+fn reused_slots_do_not_alias() {
+    let (store, _) = collecting();
+    let old = new_span(&store, 1);
+    assert!(store.drop_span(old.clone()));
+
+    let new = new_span(&store, 2);
+    assert_eq!(id_to_idx(&new), id_to_idx(&old), "the slot is reused");
+    assert_ne!(new, old);
+    assert!(store.get(&old).is_none(), "stale ID must not resolve");
+
+    // Recording into a stale ID must not affect the span which now occupies
+    // the slot.
+    record(&store, &old, 3);
+    assert_eq!(n(&store, &new), Some(Value::U64(2)));
+}
+
+#[test]
+fn slots_released_by_other_threads_are_reused() {
+    let (store, _) = collecting();
+    let store = Arc::new(store);
+    let old = new_span(&store, 1);
+    let idx = id_to_idx(&old);
+
+    // Closing the span on another thread puts its slot on the remote free
+    // list of this thread's shard.
+    let closer = store.clone();
+    thread::spawn(move || assert!(closer.drop_span(old)))
+        .join()
+        .unwrap();
+
+    let new = new_span(&store, 2);
+    assert_eq!(id_to_idx(&new), idx, "the slot is reused");
+    assert_eq!(n(&store, &new), Some(Value::U64(2)));
+    assert_eq!(store.stats().free_slots(), 0);
+}
+
+#[test]
+fn spans_span_several_pages() {
+    let (store, _) = collecting();
+    let ids = (0..slab::PAGE_SIZE as u64 * 3)
+        .map(|n| new_span(&store, n))
+        .collect::<Vec<_>>();
+    for (n, id) in ids.iter().enumerate() {
+        assert_eq!(self::n(&store, id), Some(Value::U64(n as u64)));
+    }
+    let capacity = store.stats().slab_capacity();
+    assert!(capacity >= ids.len());
+
+    for id in ids {
+        assert!(store.drop_span(id));
+    }
+    let ids = (0..slab::PAGE_SIZE as u64 * 3)
+        .map(|n| new_span(&store, n))
+        .collect::<Vec<_>>();
+    assert_eq!(store.stats().slab_capacity(), capacity, "slots are reused");
+    assert_eq!(store.stats().live_spans(), ids.len());
+}
+
+#[test]
+fn concurrent_spans() {
+    let (store, records) = collecting();
+    let store = Arc::new(store);
+    let threads = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..20 {
+                    let n = t * 100 + i;
+                    let id = new_span(&store, n);
+                    let child = new_child(&store, &id, n);
+                    assert_eq!(self::n(&store, &id), Some(Value::U64(n)));
+                    assert!(!store.drop_span(id));
+                    assert!(store.drop_span(child));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 80);
+    for record in records.iter() {
+        let n = record.root().fields().get("n");
+        assert_eq!(record.root().children()[0].fields().get("n"), n);
+    }
+    assert_eq!(store.stats().live_spans(), 0);
+}
+
+#[test]
+fn disabled_span_is_ignored() {
+    let (store, records) = collecting();
+    let id = disabled_id();
+    record(&store, &id, 1);
+    assert!(store.get(&id).is_none());
+    let clone = store.clone_span(&id);
+    assert!(!store.drop_span(clone));
+    assert!(!store.drop_span(id));
+    assert!(records.lock().unwrap().is_empty());
+    assert_eq!(store.stats().span_misuses(), 0);
+}
+
+#[test]
+fn record_panic_skips_locked_slots() {
+    let (store, _) = collecting();
+    let root = new_span(&store, 1);
+    let child = new_child(&store, &root, 2);
+    store.push(&child);
+    let record_panic = || {
+        let mut fields = Fields::new();
+        fields.insert("outcome", Value::Str("panic".to_owned()));
+        let mut event = Fields::new();
+        event.insert("message", Value::Str("oh no".to_owned()));
+        store.record_panic(fields, event)
+    };
+
+    // The root is locked, so only the event is recorded.
+    let locked = store.write_slot(&root);
+    assert!(record_panic());
+    drop(locked);
+    assert_eq!(store.get(&root).unwrap().fields().get("outcome"), None);
+    assert_eq!(store.get(&child).unwrap().events().len(), 1);
+
+    // The current span is read-locked, so only the root's fields are.
+    let locked = store.read_slot(&child);
+    assert!(record_panic());
+    drop(locked);
+    let outcome = Value::Str("panic".to_owned());
+    assert_eq!(
+        store.get(&root).unwrap().fields().get("outcome"),
+        Some(&outcome)
+    );
+    assert_eq!(store.get(&child).unwrap().events().len(), 1);
+
+    assert!(record_panic());
+    assert_eq!(store.get(&child).unwrap().events().len(), 2);
+
+    store.pop(&child);
+    assert!(!store.drop_span(root));
+    assert!(store.drop_span(child));
+}
+
+/// Panics on another thread while holding the write lock on the slot at
+/// `idx`, after calling `f` with the slot.
+fn poison(store: &Store, idx: usize, f: impl FnOnce(&mut Slot) + Send) {
+    let slot = &store.inner.get(idx).expect("slot").slot;
+    let panicked = thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let mut slot = slot.write().unwrap();
+                f(&mut slot);
+                panic!("poisoning the slot");
+            })
+            .join()
+    });
+    assert!(panicked.is_err());
+    assert!(slot.is_poisoned());
+}
+
+#[test]
+fn poisoned_slots_are_recovered() {
+    let (store, records) = collecting();
+    let id = new_span(&store, 1);
+    poison(&store, id_to_idx(&id), |_| {});
+
+    assert_eq!(n(&store, &id), Some(Value::U64(1)));
+    assert_eq!(store.stats().lock_poison_recoveries(), 1);
+    record(&store, &id, 2);
+    assert!(store.drop_span(id));
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].root().fields().get("n"), Some(&Value::U64(2)));
+    assert_eq!(store.stats().lock_poison_recoveries(), 1);
+}
+
+#[test]
+fn reading_a_poisoned_empty_slot_repairs_it() {
+    let (store, _) = collecting();
+    let old = new_span(&store, 1);
+    assert!(store.drop_span(old.clone()));
+
+    // A thread panicked while clearing the slot.
+    poison(&store, id_to_idx(&old), |slot| {
+        slot.fields.insert("stale", Value::Bool(true));
+    });
+    assert!(store.get(&old).is_none());
+    assert_eq!(store.stats().lock_poison_recoveries(), 1);
+
+    let new = new_span(&store, 2);
+    assert_eq!(id_to_idx(&new), id_to_idx(&old), "the slot is reused");
+    let span = store.get(&new).expect("span");
+    assert_eq!(span.fields().get("stale"), None);
+    assert_eq!(span.fields().get("n"), Some(&Value::U64(2)));
+}
@@ -0,0 +1,65 @@
+//! Synchronization primitives used by the span store.
+//!
+//! When built with `--cfg loom`, these are replaced by `loom`'s
+//! model-checked equivalents, so that the store's concurrency can be tested
+//! exhaustively.
+
+// `loom`'s locks return the same errors as the standard library's.
+pub(crate) use std::sync::TryLockError;
+
+#[cfg(loom)]
+pub(crate) use loom::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{fence, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread_local,
+};
+
+#[cfg(not(loom))]
+pub(crate) use std::{
+    sync::{
+        atomic::{fence, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread_local,
+};
+
+/// An `UnsafeCell` with the same interface as `loom`'s, which checks
+/// accesses to the cell when model checking.
+#[cfg(not(loom))]
+#[derive(Debug)]
+pub(crate) struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+    pub(crate) fn new(data: T) -> Self {
+        Self(std::cell::UnsafeCell::new(data))
+    }
+
+    #[inline]
+    pub(crate) fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+}
+
+/// Clears the poisoned state of a lock which has been recovered.
+#[inline]
+pub(crate) fn clear_poison<T>(lock: &RwLock<T>) {
+    // `loom` never poisons its locks.
+    #[cfg(not(loom))]
+    lock.clear_poison();
+    #[cfg(loom)]
+    let _ = lock;
+}
+
+/// Signals that we are spinning on a compare-and-swap that someone else won.
+#[inline]
+pub(crate) fn spin_loop() {
+    #[cfg(loom)]
+    loom::thread::yield_now();
+
+    #[cfg(not(loom))]
+    std::hint::spin_loop();
+}
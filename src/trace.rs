@@ -0,0 +1,313 @@
+//! Trace and span IDs, compatible with W3C Trace Context.
+use std::fmt;
+
+use crate::record::{Fields, Value};
+
+/// A 128-bit identifier shared by every span in a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceId(u128);
+
+/// A 64-bit identifier for a single span.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SpanId(u64);
+
+/// The trace a span belongs to.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Trace {
+    pub(crate) id: TraceId,
+    // The parent of the span, if it is not part of the same record: either
+    // the span in an upstream service that this trace continues, or, when
+    // each span is flushed separately, its parent span.
+    pub(crate) parent: Option<SpanId>,
+    // The rate at which the trace's root span was sampled, if it was, and
+    // whether it was kept.
+    pub(crate) sample_rate: Option<f64>,
+    pub(crate) sampled: bool,
+}
+
+// ===== impl TraceId =====
+
+impl TraceId {
+    pub fn from_u128(id: u128) -> Self {
+        Self(id)
+    }
+
+    pub fn into_u128(self) -> u128 {
+        self.0
+    }
+
+    fn random() -> Self {
+        // All-zero IDs are invalid.
+        Self(rand::random::<u128>().max(1))
+    }
+
+    /// Returns `true` if a trace with this ID is kept when sampling at
+    /// `rate`.
+    ///
+    /// The decision only depends on the ID, so every service which samples
+    /// at the same rate keeps the same traces, and a trace kept at some rate
+    /// is also kept at any higher rate.
+    fn is_sampled(self, rate: f64) -> bool {
+        // W3C Trace Context requires the rightmost 7 bytes of trace IDs to be
+        // random.
+        const RANDOM_BITS: u32 = 56;
+        let random = self.0 as u64 & ((1 << RANDOM_BITS) - 1);
+        // Compare as integers: the largest IDs round up to 2^56 as `f64`, so
+        // they wouldn't be kept even at a rate of 1.
+        let threshold = (rate * (1u64 << RANDOM_BITS) as f64) as u64;
+        random < threshold
+    }
+
+    fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 32 || !is_hex(hex) {
+            return None;
+        }
+        match u128::from_str_radix(hex, 16) {
+            Ok(0) | Err(_) => None,
+            Ok(id) => Some(Self(id)),
+        }
+    }
+}
+
+/// Formats the ID as 32 lowercase hex digits, as in a `traceparent` header.
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+// ===== impl SpanId =====
+
+impl SpanId {
+    pub fn from_u64(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn into_u64(self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn random() -> Self {
+        Self(rand::random::<u64>().max(1))
+    }
+
+    fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 16 || !is_hex(hex) {
+            return None;
+        }
+        match u64::from_str_radix(hex, 16) {
+            Ok(0) | Err(_) => None,
+            Ok(id) => Some(Self(id)),
+        }
+    }
+}
+
+/// Formats the ID as 16 lowercase hex digits, as in a `traceparent` header.
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+// ===== impl Trace =====
+
+impl Trace {
+    /// Returns the trace for a new root span with the given fields.
+    ///
+    /// If the span has a valid `traceparent` field, it continues that trace.
+    /// Otherwise, if it has a `trace_id` field of 32 hex digits, in either
+    /// case, that is used as the trace ID. Otherwise, a random trace ID is
+    /// generated. Fields recorded with `%` or `?` are accepted as well as
+    /// strings.
+    pub(crate) fn new(fields: &Fields) -> Self {
+        Self::from_fields(fields).unwrap_or_else(Self::random)
+    }
+
+    pub(crate) fn random() -> Self {
+        Self::with_id(TraceId::random(), None)
+    }
+
+    fn with_id(id: TraceId, parent: Option<SpanId>) -> Self {
+        Self {
+            id,
+            parent,
+            sample_rate: None,
+            sampled: true,
+        }
+    }
+
+    /// Decides whether to keep the trace when sampling at `rate`.
+    pub(crate) fn sample(&mut self, rate: f64) {
+        self.sample_rate = Some(rate);
+        self.sampled = self.id.is_sampled(rate);
+    }
+
+    /// Returns the trace given by a root span's `traceparent` or `trace_id`
+    /// field, if it has either.
+    pub(crate) fn from_fields(fields: &Fields) -> Option<Self> {
+        if let Some(traceparent) = fields.get("traceparent").and_then(as_str) {
+            if let Some(trace) = Self::parse_traceparent(traceparent) {
+                return Some(trace);
+            }
+        }
+        let id = fields.get("trace_id").and_then(as_str)?;
+        let id = TraceId::from_hex(&id.to_ascii_lowercase())?;
+        Some(Self::with_id(id, None))
+    }
+
+    /// Parses a W3C `traceparent` header, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    ///
+    /// As the spec requires, hex digits must be lowercase.
+    fn parse_traceparent(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+        // Later versions may add more fields, but version 00 has exactly
+        // four. Version ff is invalid.
+        let valid_version = match version {
+            "00" => parts.next().is_none(),
+            "ff" => false,
+            _ => version.len() == 2 && is_hex(version),
+        };
+        if !valid_version || flags.len() != 2 || !is_hex(flags) {
+            return None;
+        }
+        Some(Self::with_id(
+            TraceId::from_hex(trace_id)?,
+            Some(SpanId::from_hex(parent_id)?),
+        ))
+    }
+}
+
+/// Returns the string in a field value recorded as a string, or with `%` or
+/// `?`. Quotes added by `?` are removed.
+fn as_str(value: &Value) -> Option<&str> {
+    match value {
+        Value::Str(value) => Some(value),
+        Value::Debug(value) => Some(value.trim_matches('"')),
+        _ => None,
+    }
+}
+
+/// Returns `true` if `s` consists of lowercase hex digits.
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn parse(header: &str) -> Option<(u128, u64)> {
+        let trace = Trace::parse_traceparent(header)?;
+        Some((trace.id.into_u128(), trace.parent?.into_u64()))
+    }
+
+    fn fields(name: &'static str, value: Value) -> Fields {
+        let mut fields = Fields::new();
+        fields.insert(name, value);
+        fields
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        assert_eq!(
+            parse(TRACEPARENT),
+            Some((0x4bf92f3577b34da6a3ce929d0e0e4736, 0x00f067aa0ba902b7))
+        );
+        assert!(parse(&format!(" {} ", TRACEPARENT)).is_some());
+        // Later versions may have more fields.
+        assert!(parse(&format!("cc{}-extra", &TRACEPARENT[2..])).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_traceparent() {
+        let invalid = [
+            "",
+            // Uppercase hex.
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00F067AA0BA902B7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0A",
+            // Invalid version, or extra fields in version 00.
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            // All-zero IDs.
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            // Wrong lengths.
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            // Signs, which `from_str_radix` would accept.
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ];
+        for header in &invalid {
+            assert_eq!(parse(header), None, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn trace_from_fields() {
+        let expected = TraceId::from_u128(0x4bf92f3577b34da6a3ce929d0e0e4736);
+        let traceparent = [
+            Value::Str(TRACEPARENT.to_owned()),
+            // Recorded with `%`.
+            Value::Debug(TRACEPARENT.to_owned()),
+            // Recorded with `?`.
+            Value::Debug(format!("{:?}", TRACEPARENT)),
+        ];
+        for value in &traceparent {
+            let trace = Trace::from_fields(&fields("traceparent", value.clone()));
+            let trace = trace.expect("valid traceparent");
+            assert_eq!(trace.id, expected);
+            assert_eq!(trace.parent, Some(SpanId::from_u64(0x00f067aa0ba902b7)));
+        }
+
+        let trace_id = [
+            Value::Str("4bf92f3577b34da6a3ce929d0e0e4736".to_owned()),
+            Value::Debug("4BF92F3577B34DA6A3CE929D0E0E4736".to_owned()),
+        ];
+        for value in &trace_id {
+            let trace = Trace::from_fields(&fields("trace_id", value.clone()));
+            let trace = trace.expect("valid trace_id");
+            assert_eq!(trace.id, expected);
+            assert_eq!(trace.parent, None);
+        }
+
+        assert!(Trace::from_fields(&fields("trace_id", Value::U64(1))).is_none());
+        assert!(Trace::from_fields(&Fields::new()).is_none());
+    }
+
+    #[test]
+    fn sampling_depends_on_the_random_bits() {
+        let id = |random: u64| TraceId::from_u128((u128::MAX << 56) | random as u128);
+        // Just below 2^55, `f64` can only represent multiples of 8.
+        let half = 1 << 55;
+        assert!(id(0).is_sampled(0.5));
+        assert!(id(half - 8).is_sampled(0.5));
+        assert!(!id(half).is_sampled(0.5));
+        assert!(!id(0).is_sampled(0.0));
+        assert!(id((1 << 56) - 1).is_sampled(1.0));
+
+        // A trace kept at some rate is kept at any higher rate.
+        for i in 0..1000u64 {
+            let id = id(i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 8);
+            if id.is_sampled(0.1) {
+                assert!(id.is_sampled(0.2));
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_keeps_about_the_given_rate() {
+        let kept = (0..10_000)
+            .filter(|_| TraceId::random().is_sampled(0.25))
+            .count();
+        assert!((2000..3000).contains(&kept), "kept {}", kept);
+    }
+}
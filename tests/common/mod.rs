@@ -0,0 +1,49 @@
+//! Fixtures shared by the integration tests.
+//!
+//! Each test file only uses some of these.
+#![allow(dead_code)]
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tracing_concat::{Builder, ConcatRecord, TracingConcat};
+
+/// The records flushed by a subscriber built with [`collecting`].
+pub type Records = Arc<Mutex<Vec<ConcatRecord>>>;
+
+/// Builds a subscriber from `builder` which collects the records it flushes.
+pub fn collecting(builder: Builder) -> (TracingConcat, Records) {
+    let records = Records::default();
+    let flushed = records.clone();
+    let concat = builder
+        .on_flush(move |record| flushed.lock().unwrap().push(record.clone()))
+        .build();
+    (concat, records)
+}
+
+/// An `io::Write`r which appends to a shared buffer.
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    /// Returns the lines written to the buffer so far.
+    pub fn lines(&self) -> Vec<String> {
+        let written = self.0.lock().unwrap();
+        String::from_utf8(written.clone())
+            .unwrap()
+            .lines()
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+}
+
+impl io::Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
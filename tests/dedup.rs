@@ -0,0 +1,100 @@
+use std::{thread, time::Duration};
+use tracing::{info, info_span, subscriber, Level};
+use tracing_concat::{ConcatRecord, Dedup, TracingConcat, Value};
+
+mod common;
+use common::collecting;
+
+/// Runs `f` with events deduplicated by `dedup`, and returns the one record
+/// flushed.
+fn dedup(dedup: Dedup, f: impl FnOnce()) -> ConcatRecord {
+    let (concat, records) = collecting(TracingConcat::builder().with_dedup(dedup));
+    subscriber::with_default(concat, f);
+    let mut records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    records.remove(0)
+}
+
+/// Logs `retrying` four times from one callsite, with alternating fields.
+fn retry() {
+    for attempt in 0..4u64 {
+        info!(attempt = attempt % 2, "retrying");
+    }
+}
+
+#[test]
+fn callsite_and_fields_collapses_identical_events() {
+    let record = dedup(Dedup::CallsiteAndFields, || {
+        info_span!("request").in_scope(|| {
+            retry();
+            // The same fields from another callsite are not a repeat.
+            info!(attempt = 0u64, "retrying");
+        })
+    });
+    let events = record.root().events();
+    let summary = events
+        .iter()
+        .map(|event| (event.fields().get("attempt").cloned(), event.repeated()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            (Some(Value::U64(0)), 2),
+            (Some(Value::U64(1)), 2),
+            (Some(Value::U64(0)), 1),
+        ]
+    );
+    assert_eq!(record.event_count(Level::INFO), 5);
+}
+
+#[test]
+fn callsite_collapses_events_whatever_their_fields() {
+    let record = dedup(Dedup::Callsite, || info_span!("request").in_scope(retry));
+    let events = record.root().events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].repeated(), 4);
+    // The collapsed event keeps the fields of the first occurrence.
+    assert_eq!(events[0].fields().get("attempt"), Some(&Value::U64(0)));
+    assert_eq!(record.event_count(Level::INFO), 4);
+}
+
+#[test]
+fn repeats_in_other_spans_are_not_collapsed() {
+    let record = dedup(Dedup::Callsite, || {
+        info_span!("request").in_scope(|| {
+            retry();
+            info_span!("db").in_scope(retry);
+        })
+    });
+    let root = record.root();
+    assert_eq!(root.events().len(), 1);
+    assert_eq!(root.events()[0].repeated(), 4);
+    let db = &root.children()[0];
+    assert_eq!(db.events().len(), 1);
+    assert_eq!(db.events()[0].repeated(), 4);
+}
+
+#[test]
+fn last_timestamp_is_when_the_last_repeat_occurred() {
+    let record = dedup(Dedup::Callsite, || {
+        info_span!("request").in_scope(|| {
+            for _ in 0..3 {
+                info!("polled");
+                thread::sleep(Duration::from_millis(10));
+            }
+            info!("done");
+        })
+    });
+    let events = record.root().events();
+    let (polled, done) = (&events[0], &events[1]);
+    assert_eq!(polled.repeated(), 3);
+    let elapsed = polled
+        .last_timestamp()
+        .duration_since(polled.timestamp())
+        .unwrap();
+    assert!(elapsed >= Duration::from_millis(20), "{:?}", elapsed);
+    assert!(polled.last_timestamp() <= done.timestamp());
+
+    assert_eq!(done.repeated(), 1);
+    assert_eq!(done.last_timestamp(), done.timestamp());
+}
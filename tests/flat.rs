@@ -0,0 +1,159 @@
+use serde_json::{Map, Value};
+use tracing::{info, info_span, subscriber};
+use tracing_concat::{
+    format::{Collision, Flat},
+    TracingConcat, WriterSink,
+};
+
+mod common;
+use common::Buffer;
+
+/// Runs `f`, writing records with `Flat` as JSON, and returns the objects
+/// written.
+fn flatten(
+    flat: Flat,
+    max_record_bytes: Option<usize>,
+    f: impl FnOnce(),
+) -> Vec<Map<String, Value>> {
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let mut builder =
+        TracingConcat::builder().with_sink(WriterSink::new(flat.json(), move || writer.clone()));
+    if let Some(max) = max_record_bytes {
+        builder = builder.with_max_record_bytes(max);
+    }
+    subscriber::with_default(builder.build(), f);
+    buffer
+        .lines()
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+/// A request whose span fields reuse the keys of record metadata.
+fn request() {
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let span = info_span!(
+        "request",
+        %traceparent,
+        name = "field",
+        trace_id = "field",
+        level = "field",
+        warn_count = "field",
+        duration_ms = "field"
+    );
+    span.in_scope(|| {
+        info_span!(
+            "db",
+            table = "users",
+            duration_ms = "field",
+            span_id = "field"
+        )
+        .in_scope(|| info!("queried"));
+    });
+}
+
+#[test]
+fn writes_record_and_span_metadata() {
+    let records = flatten(Flat::new(), None, request);
+    let record = &records[0];
+    assert_eq!(record["name"], "request");
+    assert_eq!(record["record_id"].as_str().unwrap().len(), 16);
+    assert_eq!(record["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(record["parent_span_id"], "00f067aa0ba902b7");
+    assert_eq!(record["span_id"].as_str().unwrap().len(), 16);
+    assert_eq!(record["db.span_id"].as_str().unwrap().len(), 16);
+    assert_ne!(record["span_id"], record["db.span_id"]);
+    assert_eq!(record["db.table"], "users");
+    assert!(record.get("part").is_none());
+}
+
+#[test]
+fn metadata_takes_precedence_over_fields() {
+    for &collision in &[Collision::ChildWins, Collision::ParentWins] {
+        let records = flatten(Flat::new().with_collision(collision), None, request);
+        let record = &records[0];
+        assert_eq!(record["name"], "request");
+        assert_eq!(record["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["warn_count"], 0);
+        assert!(record["duration_ms"].is_f64());
+        assert!(record["db.duration_ms"].is_f64());
+        assert_ne!(record["db.span_id"], "field");
+        assert!(
+            record.values().all(|value| value != "field"),
+            "{:?}",
+            record
+        );
+    }
+}
+
+#[test]
+fn keep_both_suffixes_fields_which_reuse_metadata_keys() {
+    let records = flatten(
+        Flat::new().with_collision(Collision::KeepBoth),
+        None,
+        request,
+    );
+    let record = &records[0];
+    assert_eq!(record["name"], "request");
+    assert_eq!(record["name_2"], "field");
+    assert_eq!(record["level"], "INFO");
+    assert_eq!(record["level_2"], "field");
+    assert!(record["db.duration_ms"].is_f64());
+    assert_eq!(record["db.duration_ms_2"], "field");
+    assert_eq!(record["db.span_id_2"], "field");
+}
+
+#[test]
+fn parts_share_a_record_id() {
+    let records = flatten(Flat::new(), Some(1), || {
+        info_span!("request", user = "someone").in_scope(|| {
+            info!("first");
+            info!("second");
+        })
+    });
+    assert_eq!(records.len(), 3);
+    for (i, record) in records.iter().enumerate() {
+        assert_eq!(record["record_id"], records[0]["record_id"]);
+        assert_eq!(record["part"], i + 1);
+        assert_eq!(record["parts"], 3);
+    }
+}
+
+/// A request which queries the database twice, connecting the first time.
+fn queries() {
+    info_span!("request").in_scope(|| {
+        info_span!("db", table = "users").in_scope(|| {
+            info_span!("connect", host = "primary").in_scope(|| info!("connected"));
+        });
+        info_span!("db", table = "orders").in_scope(|| info!("queried"));
+    });
+}
+
+#[test]
+fn keys_are_prefixed_with_the_span_path() {
+    let records = flatten(Flat::new().with_separator("/"), None, queries);
+    let record = &records[0];
+    assert_eq!(record["db/connect/host"], "primary");
+    assert!(record["db/connect/duration_ms"].is_f64());
+    assert!(record.get("connect/host").is_none());
+}
+
+#[test]
+fn collisions_between_spans_with_the_same_path() {
+    let table = |collision| {
+        let records = flatten(Flat::new().with_collision(collision), None, queries);
+        let record = records[0].clone();
+        (
+            record["db.table"].clone(),
+            record.get("db.table_2").cloned(),
+        )
+    };
+    assert_eq!(table(Collision::ChildWins), ("orders".into(), None));
+    assert_eq!(table(Collision::ParentWins), ("users".into(), None));
+    assert_eq!(
+        table(Collision::KeepBoth),
+        ("users".into(), Some("orders".into()))
+    );
+}
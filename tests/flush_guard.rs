@@ -0,0 +1,129 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{info, info_span, subscriber};
+use tracing_concat::{capture, ConcatRecord, ConcatSink, FlushMode, TracingConcat};
+
+mod common;
+use common::collecting;
+
+/// A sink which counts how many times it was flushed.
+struct Flushes(Arc<AtomicUsize>);
+
+impl ConcatSink for Flushes {
+    fn on_flush(&self, _: &ConcatRecord) {}
+
+    fn flush(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn flushes_open_roots_as_incomplete() {
+    let (concat, records) = collecting(TracingConcat::builder());
+    let guard = concat.flush_guard();
+    subscriber::with_default(concat, || {
+        let request = info_span!("request");
+        let _request = request.enter();
+        info!("started");
+        info_span!("db").in_scope(|| info!("queried"));
+        let handler = info_span!("handler");
+        let _handler = handler.enter();
+        info!("handling");
+
+        drop(guard);
+        {
+            let records = records.lock().unwrap();
+            assert_eq!(records.len(), 1);
+            let record = &records[0];
+            assert!(record.is_incomplete());
+            let root = record.root();
+            assert_eq!(root.name(), "request");
+            assert_eq!(root.events().len(), 1);
+            let children = root.children().iter().map(|child| child.name());
+            assert_eq!(children.collect::<Vec<_>>(), ["db", "handler"]);
+            assert_eq!(root.children()[1].events().len(), 1);
+        }
+    });
+
+    // The spans were left open, and flushed in full when they closed.
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(!records[1].is_incomplete());
+    assert_eq!(records[1].root().children().len(), 2);
+}
+
+#[test]
+fn flushes_each_open_span_when_flushing_each_span() {
+    let builder = TracingConcat::builder().with_flush_mode(FlushMode::EachSpan);
+    let (concat, records) = collecting(builder);
+    let guard = concat.flush_guard();
+    subscriber::with_default(concat, || {
+        info_span!("request").in_scope(|| {
+            info_span!("handler").in_scope(|| {
+                info!("handling");
+                drop(guard);
+            })
+        })
+    });
+    let records = records.lock().unwrap();
+    let flushed = records
+        .iter()
+        .map(|record| (record.root().name(), record.is_incomplete()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        flushed,
+        [
+            ("request", true),
+            ("handler", true),
+            ("handler", false),
+            ("request", false),
+        ]
+    );
+}
+
+#[test]
+fn skips_spans_being_captured() {
+    let (concat, records) = collecting(TracingConcat::builder());
+    let guard = concat.flush_guard();
+    let (_, captured) = subscriber::with_default(concat, || {
+        capture(|| {
+            info_span!("db").in_scope(|| {
+                info!("queried");
+                drop(guard);
+            })
+        })
+    });
+    assert!(records.lock().unwrap().is_empty());
+    assert!(!captured.is_incomplete());
+    assert_eq!(captured.root().children()[0].name(), "db");
+}
+
+#[test]
+fn flushes_sinks_and_rate_limit_summaries() {
+    let flushes = Arc::new(AtomicUsize::new(0));
+    let builder = TracingConcat::builder()
+        .with_sink(Flushes(flushes.clone()))
+        .with_rate_limit("request", 1, Duration::from_secs(60 * 60));
+    let (concat, records) = collecting(builder);
+    let guard = concat.flush_guard();
+    subscriber::with_default(concat, || {
+        for _ in 0..3 {
+            info_span!("request").in_scope(|| info!("handled"));
+        }
+    });
+    assert_eq!(records.lock().unwrap().len(), 1);
+    assert_eq!(flushes.load(Ordering::Relaxed), 0);
+
+    drop(guard);
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    let summary = records[1].root();
+    assert_eq!(summary.name(), "rate_limited");
+    assert_eq!(summary.fields().get("suppressed").unwrap().to_string(), "2");
+    assert_eq!(flushes.load(Ordering::Relaxed), 1);
+}
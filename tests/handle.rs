@@ -0,0 +1,54 @@
+use tracing::{info, info_span, subscriber};
+use tracing_concat::TracingConcat;
+
+mod common;
+use common::collecting;
+
+#[test]
+fn visit_spans_sees_events_and_closed_children() {
+    let (concat, records) = collecting(TracingConcat::builder());
+    let handle = concat.handle();
+
+    subscriber::with_default(concat, || {
+        let request = info_span!("request", id = 1);
+        let _request = request.enter();
+        info!("started");
+        info_span!("db").in_scope(|| info!("queried"));
+        let handler = info_span!("handler");
+        let _handler = handler.enter();
+
+        let mut names = Vec::new();
+        handle
+            .visit_spans(|_, span| {
+                // Logging and recording while visiting must not deadlock.
+                info!(span = span.name(), "visited");
+                request.record("id", 2);
+                names.push(span.name());
+                if span.name() == "request" {
+                    assert_eq!(span.events().len(), 1);
+                    assert_eq!(span.children().len(), 1);
+                    assert_eq!(span.children()[0].name(), "db");
+                    assert_eq!(span.children()[0].events().len(), 1);
+                }
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(names, ["request", "handler"]);
+
+        let current = handle.with_current(|(_, span)| {
+            info_span!("nested").in_scope(|| info!("inside with_current"));
+            span.name()
+        });
+        assert_eq!(current, Some("handler"));
+    });
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    let root = records[0].root();
+    assert_eq!(
+        root.fields().get("id").map(ToString::to_string),
+        Some("2".into())
+    );
+    let handler = root.children().iter().find(|span| span.name() == "handler");
+    assert_eq!(handler.expect("handler span").events().len(), 2);
+}
@@ -0,0 +1,121 @@
+use std::{sync::Mutex, thread, time::Duration};
+use tracing::{info, info_span, subscriber, warn, Level};
+use tracing_concat::{ConcatRecord, Handle, Heartbeat, TracingConcat};
+
+mod common;
+use common::{collecting, Records};
+
+/// Returns a subscriber with the given heartbeat for `connection` spans, a
+/// handle to it, and the records it flushes.
+fn with_heartbeat(heartbeat: Heartbeat) -> (TracingConcat, Handle, Records) {
+    let (concat, records) =
+        collecting(TracingConcat::builder().with_heartbeat("connection", heartbeat));
+    let handle = concat.handle();
+    (concat, handle, records)
+}
+
+/// Returns the snapshot number and event count of each record flushed so
+/// far.
+fn heartbeats(records: &Mutex<Vec<ConcatRecord>>) -> Vec<(Option<u64>, usize)> {
+    let records = records.lock().unwrap();
+    records
+        .iter()
+        .map(|record| (record.snapshot(), record.root().events().len()))
+        .collect()
+}
+
+#[test]
+fn interval_heartbeats_are_flushed_for_idle_spans() {
+    let interval = Duration::from_millis(50);
+    let (concat, handle, records) = with_heartbeat(Heartbeat::new().with_interval(interval));
+
+    subscriber::with_default(concat, || {
+        let span = info_span!("connection");
+        let _span = span.enter();
+        info!("opened");
+        handle.flush_due();
+        assert_eq!(heartbeats(&records), [], "the interval has not elapsed");
+
+        thread::sleep(interval + Duration::from_millis(10));
+        handle.flush_due();
+        assert_eq!(heartbeats(&records), [(Some(1), 1)]);
+
+        // Nothing is recorded, but the span still gets a heartbeat.
+        thread::sleep(interval + Duration::from_millis(10));
+        handle.flush_due();
+        assert_eq!(heartbeats(&records), [(Some(1), 1), (Some(2), 0)]);
+    });
+
+    let heartbeats = heartbeats(&records);
+    assert_eq!(heartbeats.len(), 3);
+    assert_eq!(heartbeats[2], (Some(3), 0));
+    let records = records.lock().unwrap();
+    assert!(records[0].is_incomplete() && records[1].is_incomplete());
+    assert!(!records[2].is_incomplete());
+}
+
+#[test]
+fn event_count_heartbeats_are_flushed_when_events_are_recorded() {
+    let (concat, handle, records) = with_heartbeat(Heartbeat::new().with_max_events(2));
+
+    subscriber::with_default(concat, || {
+        info_span!("connection").in_scope(|| {
+            info!("one");
+            assert_eq!(heartbeats(&records), []);
+            info!("two");
+            assert_eq!(heartbeats(&records), [(Some(1), 2)]);
+            info!("three");
+            handle.flush_due();
+            assert_eq!(heartbeats(&records), [(Some(1), 2)], "not due yet");
+        });
+        // Other spans don't get heartbeats.
+        info_span!("request").in_scope(|| {
+            info!("one");
+            info!("two");
+        });
+    });
+
+    assert_eq!(
+        heartbeats(&records),
+        [(Some(1), 2), (Some(2), 1), (None, 2)]
+    );
+}
+
+#[test]
+fn sampled_out_roots_get_no_heartbeats() {
+    let builder = TracingConcat::builder()
+        .with_sample_rate(0.0)
+        .with_heartbeat("connection", Heartbeat::new().with_interval(Duration::ZERO));
+    let (concat, records) = collecting(builder);
+    let handle = concat.handle();
+    subscriber::with_default(concat, || {
+        info_span!("connection").in_scope(|| {
+            info!("opened");
+            info_span!("request").in_scope(|| info!("handled"));
+            handle.flush_due();
+        })
+    });
+    assert_eq!(heartbeats(&records), []);
+}
+
+#[test]
+fn roots_below_the_level_get_heartbeats_once_something_is_kept() {
+    let builder = TracingConcat::builder()
+        .with_max_level(Level::WARN)
+        .with_heartbeat("connection", Heartbeat::new().with_interval(Duration::ZERO));
+    let (concat, records) = collecting(builder);
+    let handle = concat.handle();
+    subscriber::with_default(concat, || {
+        info_span!("connection").in_scope(|| {
+            info!("filtered");
+            handle.flush_due();
+            assert_eq!(heartbeats(&records), []);
+
+            warn!("kept");
+            assert_eq!(heartbeats(&records), [(Some(1), 1)]);
+            handle.flush_due();
+            assert_eq!(heartbeats(&records), [(Some(1), 1)], "empty again");
+        })
+    });
+    assert_eq!(heartbeats(&records), [(Some(1), 1)]);
+}
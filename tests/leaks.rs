@@ -0,0 +1,54 @@
+use std::{thread, time::Duration};
+use tracing::{info_span, subscriber, Level};
+use tracing_concat::{TracingConcat, Value};
+
+mod common;
+use common::collecting;
+
+#[test]
+fn report_leaks_lists_spans_older_than_the_threshold() {
+    let (concat, records) = collecting(TracingConcat::builder());
+    let handle = concat.handle();
+    let older_than = Duration::from_millis(50);
+
+    subscriber::with_default(concat, || {
+        let request = info_span!("request");
+        let _request = request.enter();
+        let db_line = line!() + 1;
+        let db = info_span!("db", table = "users");
+        let _db = db.enter();
+        thread::sleep(older_than + Duration::from_millis(10));
+        let fresh = info_span!("fresh");
+        let _fresh = fresh.enter();
+
+        assert_eq!(handle.report_leaks(Duration::from_secs(3600)), 0);
+        assert!(records.lock().unwrap().is_empty(), "nothing to report");
+
+        assert_eq!(handle.report_leaks(older_than), 2);
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        let report = &records[0];
+        assert_eq!(report.level(), Level::WARN);
+        let root = report.root();
+        assert_eq!(root.name(), "leaked_spans");
+        assert_eq!(root.fields().get("count"), Some(&Value::U64(2)));
+        assert_eq!(root.fields().get("older_than_ms"), Some(&Value::U64(50)));
+
+        // The oldest spans are listed first.
+        let leaked = root.children();
+        let names = leaked.iter().map(|span| span.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["request", "db"]);
+        assert!(leaked.iter().all(|span| span.duration() >= older_than));
+
+        let db = &leaked[1];
+        let field = |name| db.fields().get(name).cloned();
+        assert_eq!(field("table"), Some(Value::Str("users".into())));
+        assert_eq!(field("parents"), Some(Value::Str("request".into())));
+        let callsite = format!("{}:{}", file!(), db_line);
+        assert_eq!(field("callsite"), Some(Value::Str(callsite)));
+        assert_eq!(
+            leaked[0].fields().get("parents"),
+            Some(&Value::Str(String::new()))
+        );
+    });
+}
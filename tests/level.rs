@@ -0,0 +1,136 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tracing::{
+    debug, debug_span, dispatcher, info, info_span, subscriber, warn, Dispatch, Event, Level,
+    Subscriber,
+};
+use tracing_concat::{ConcatRecord, TracingConcat};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+mod common;
+use common::collecting;
+
+/// Runs `f` with the level set to `WARN`, and returns the records flushed
+/// and the number of events recorded outside of any span.
+fn with_warn(f: impl FnOnce()) -> (Vec<ConcatRecord>, usize) {
+    let (concat, records) = collecting(TracingConcat::builder().with_max_level(Level::WARN));
+    let dispatch = Dispatch::new(concat);
+    dispatcher::with_default(&dispatch, f);
+    let stats = dispatch.downcast_ref::<TracingConcat>().unwrap().stats();
+    let records = records.lock().unwrap().clone();
+    (records, stats.events_outside_spans())
+}
+
+fn messages(record: &ConcatRecord) -> Vec<String> {
+    fn collect(span: &tracing_concat::SpanRecord, messages: &mut Vec<String>) {
+        for event in span.events() {
+            messages.push(event.fields().get("message").unwrap().to_string());
+        }
+        for child in span.children() {
+            collect(child, messages);
+        }
+    }
+    let mut messages = Vec::new();
+    collect(record.root(), &mut messages);
+    messages
+}
+
+#[test]
+fn root_below_level_can_lower_it() {
+    let (records, outside) = with_warn(|| {
+        info_span!("request", log.level = "trace").in_scope(|| {
+            debug!("kept");
+            debug_span!("db").in_scope(|| debug!("also kept"));
+            warn!("warned");
+        });
+    });
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.root().name(), "request");
+    assert_eq!(record.root().children()[0].name(), "db");
+    assert_eq!(messages(record), ["kept", "warned", "also kept"]);
+    assert_eq!(outside, 0);
+}
+
+#[test]
+fn other_roots_keep_the_configured_level() {
+    let (records, outside) = with_warn(|| {
+        info_span!("request", log.level = "trace").in_scope(|| debug!("kept"));
+        info_span!("other").in_scope(|| {
+            debug!("dropped");
+            info!("dropped");
+            debug_span!("db").in_scope(|| warn!("warned in db"));
+            warn!("warned");
+        });
+    });
+    assert_eq!(records.len(), 2);
+    let other = &records[1];
+    assert_eq!(other.root().name(), "other");
+    // `db` is below the level, so its event belongs to its parent.
+    assert!(other.root().children().is_empty());
+    assert_eq!(messages(other), ["warned in db", "warned"]);
+    assert_eq!(outside, 0);
+}
+
+#[test]
+fn empty_root_below_level_is_not_flushed() {
+    let (records, _) = with_warn(|| {
+        info_span!("request").in_scope(|| info!("dropped"));
+    });
+    assert!(records.is_empty());
+}
+
+#[test]
+fn level_can_be_lowered_after_the_root_is_created() {
+    let (records, _) = with_warn(|| {
+        let span = info_span!("request", log.level = tracing::field::Empty);
+        let _span = span.enter();
+        debug!("dropped");
+        span.record("log.level", "debug");
+        debug!("kept");
+    });
+    assert_eq!(records.len(), 1);
+    assert_eq!(messages(&records[0]), ["kept"]);
+}
+
+/// A layer which counts the events it sees.
+struct Count(Arc<AtomicUsize>);
+
+impl<S: Subscriber> Layer<S> for Count {
+    fn on_event(&self, _: &Event<'_>, _: Context<'_, S>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn layer_level_does_not_filter_other_layers() {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let flushed = records.clone();
+    let layer = TracingConcat::builder()
+        .with_max_level(Level::WARN)
+        .on_flush(move |record| flushed.lock().unwrap().push(record.clone()))
+        .build_layer();
+    let seen = Arc::new(AtomicUsize::new(0));
+    let registry = tracing_subscriber::registry()
+        .with(layer)
+        .with(Count(seen.clone()));
+    subscriber::with_default(registry, || {
+        info_span!("request", log.level = "trace").in_scope(|| debug!("kept"));
+        info_span!("other").in_scope(|| {
+            debug!("dropped");
+            info!("dropped");
+            debug_span!("db").in_scope(|| warn!("warned in db"));
+            warn!("warned");
+        });
+    });
+    assert_eq!(seen.load(Ordering::Relaxed), 5);
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(messages(&records[0]), ["kept"]);
+    let other = &records[1];
+    assert!(other.root().children().is_empty());
+    assert_eq!(messages(other), ["warned in db", "warned"]);
+}
@@ -0,0 +1,84 @@
+use std::panic::{self, AssertUnwindSafe};
+use tracing::{dispatcher, info_span, Dispatch};
+use tracing_concat::{Fields, Stats, TracingConcat, Value};
+
+mod common;
+use common::collecting;
+
+/// Exits a span while another is current, then closes a span twice and
+/// clones it after it closed, returning the fields of the `span_misuse`
+/// records flushed, the statistics, and the callsites of the spans misused,
+/// in that order.
+fn misuse(builder: tracing_concat::Builder) -> (Vec<Fields>, Stats, [String; 3]) {
+    let (concat, records) = collecting(builder);
+    let dispatch = Dispatch::new(concat);
+    let callsite = |line| format!("{}:{}", file!(), line);
+    let mut callsites = [String::new(), String::new(), String::new()];
+
+    dispatcher::with_default(&dispatch, || {
+        callsites[0] = callsite(line!() + 1);
+        let outer = info_span!("outer");
+        callsites[1] = callsite(line!() + 1);
+        let inner = info_span!("inner");
+        let outer = outer.enter();
+        let inner = inner.enter();
+        drop(outer);
+        drop(inner);
+
+        callsites[2] = callsite(line!() + 1);
+        let span = info_span!("closed");
+        let id = dispatch.clone_span(&span.id().unwrap());
+        drop(span);
+        assert!(dispatch.try_close(id.clone()));
+        // Misusing a closed span panics in debug builds, after it was
+        // reported.
+        let closed = panic::catch_unwind(AssertUnwindSafe(|| dispatch.try_close(id.clone())));
+        assert_eq!(closed.is_err(), cfg!(debug_assertions));
+        let cloned = panic::catch_unwind(AssertUnwindSafe(|| dispatch.clone_span(&id)));
+        assert_eq!(cloned.is_err(), cfg!(debug_assertions));
+    });
+
+    let stats = dispatch.downcast_ref::<TracingConcat>().unwrap().stats();
+    let reports = records
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|record| record.root().name() == "span_misuse")
+        .map(|record| record.root().fields().clone())
+        .collect();
+    (reports, stats, callsites)
+}
+
+fn str(value: &str) -> Option<Value> {
+    Some(Value::Str(value.to_owned()))
+}
+
+#[test]
+fn strict_diagnostics_report_each_misuse() {
+    let builder = TracingConcat::builder().with_strict_diagnostics();
+    let (reports, stats, [outer, inner, closed]) = misuse(builder);
+    assert_eq!(stats.span_misuses(), 3);
+    assert_eq!(reports.len(), 3, "{:#?}", reports);
+    let field = |report: &Fields, name| report.get(name).cloned();
+
+    let exit = &reports[0];
+    assert_eq!(field(exit, "kind"), str("exit_not_current"));
+    assert_eq!(field(exit, "span"), str("outer"));
+    assert_eq!(field(exit, "callsite"), str(&outer));
+    assert_eq!(field(exit, "current"), str("inner"));
+    assert_eq!(field(exit, "current_callsite"), str(&inner));
+
+    // Spans which already closed are still named.
+    for (report, kind) in reports[1..].iter().zip(&["drop_closed", "clone_closed"]) {
+        assert_eq!(field(report, "kind"), str(kind));
+        assert_eq!(field(report, "span"), str("closed"));
+        assert_eq!(field(report, "callsite"), str(&closed));
+    }
+}
+
+#[test]
+fn misuses_are_counted_without_strict_diagnostics() {
+    let (reports, stats, _) = misuse(TracingConcat::builder());
+    assert_eq!(stats.span_misuses(), 3);
+    assert_eq!(reports, []);
+}
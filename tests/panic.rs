@@ -0,0 +1,40 @@
+use std::panic;
+use tracing::{info, info_span, subscriber, Level};
+use tracing_concat::{PanicHook, TracingConcat, Value};
+
+mod common;
+use common::collecting;
+
+#[test]
+fn panic_inside_span_is_recorded() {
+    PanicHook::new().install();
+    let (concat, records) = collecting(TracingConcat::builder());
+
+    subscriber::with_default(concat, || {
+        let result = panic::catch_unwind(|| {
+            info_span!("request").in_scope(|| {
+                info!("started");
+                info_span!("handler").in_scope(|| panic!("oh no"));
+            })
+        });
+        assert!(result.is_err());
+    });
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.level(), Level::ERROR);
+
+    let root = record.root();
+    let str = |value: &str| Value::Str(value.to_owned());
+    assert_eq!(root.fields().get("outcome"), Some(&str("panic")));
+    assert_eq!(root.fields().get("panic.message"), Some(&str("oh no")));
+    assert!(root.fields().get("panic.location").is_some());
+    assert_eq!(root.events().len(), 1);
+
+    let handler = &root.children()[0];
+    assert_eq!(handler.name(), "handler");
+    let event = &handler.events()[0];
+    assert_eq!(event.metadata().name(), "panic");
+    assert_eq!(*event.metadata().level(), Level::ERROR);
+}
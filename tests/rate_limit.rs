@@ -0,0 +1,101 @@
+use std::{sync::Mutex, thread, time::Duration};
+use tracing::{dispatcher, info, info_span, Dispatch};
+use tracing_concat::{ConcatRecord, FlushMode, Heartbeat, TracingConcat, Value};
+
+mod common;
+use common::collecting;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+fn names(records: &Mutex<Vec<ConcatRecord>>) -> Vec<&'static str> {
+    let records = records.lock().unwrap();
+    records.iter().map(|record| record.root().name()).collect()
+}
+
+fn rate_limited(dispatch: &Dispatch) -> usize {
+    let concat = dispatch.downcast_ref::<TracingConcat>().unwrap();
+    concat.stats().records_rate_limited()
+}
+
+#[test]
+fn limits_completed_root_records() {
+    let builder = TracingConcat::builder().with_rate_limit("request", 1, HOUR);
+    let (concat, records) = collecting(builder);
+    let dispatch = Dispatch::new(concat);
+    dispatcher::with_default(&dispatch, || {
+        for _ in 0..3 {
+            info_span!("request").in_scope(|| info!("handled"));
+        }
+        info_span!("other").in_scope(|| info!("handled"));
+    });
+    assert_eq!(names(&records), ["request", "other"]);
+    assert_eq!(rate_limited(&dispatch), 2);
+}
+
+#[test]
+fn heartbeats_are_not_limited() {
+    let builder = TracingConcat::builder()
+        .with_rate_limit("connection", 1, HOUR)
+        .with_heartbeat("connection", Heartbeat::new().with_max_events(1));
+    let (concat, records) = collecting(builder);
+    let dispatch = Dispatch::new(concat);
+    dispatcher::with_default(&dispatch, || {
+        info_span!("connection").in_scope(|| {
+            for _ in 0..3 {
+                info!("received");
+            }
+        });
+    });
+    let records = records.lock().unwrap();
+    let snapshots = records
+        .iter()
+        .map(|record| record.snapshot())
+        .collect::<Vec<_>>();
+    assert_eq!(snapshots, [Some(1), Some(2), Some(3), Some(4)]);
+    assert_eq!(rate_limited(&dispatch), 0);
+}
+
+#[test]
+fn child_records_are_not_limited() {
+    let builder = TracingConcat::builder()
+        .with_flush_mode(FlushMode::EachSpan)
+        .with_rate_limit("db", 1, HOUR);
+    let (concat, records) = collecting(builder);
+    let dispatch = Dispatch::new(concat);
+    dispatcher::with_default(&dispatch, || {
+        info_span!("request").in_scope(|| {
+            for _ in 0..3 {
+                info_span!("db").in_scope(|| info!("queried"));
+            }
+        });
+    });
+    assert_eq!(names(&records), ["db", "db", "db", "request"]);
+    assert_eq!(rate_limited(&dispatch), 0);
+}
+
+#[test]
+fn flush_due_flushes_summaries() {
+    let builder = TracingConcat::builder().with_rate_limit("request", 1, Duration::from_millis(50));
+    let (concat, records) = collecting(builder);
+    let handle = concat.handle();
+    let dispatch = Dispatch::new(concat);
+    dispatcher::with_default(&dispatch, || {
+        for _ in 0..3 {
+            info_span!("request").in_scope(|| info!("handled"));
+        }
+    });
+    handle.flush_due();
+    assert_eq!(names(&records), ["request"], "the period has not elapsed");
+
+    thread::sleep(Duration::from_millis(60));
+    handle.flush_due();
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    let summary = records[1].root();
+    assert_eq!(summary.name(), "rate_limited");
+    assert_eq!(
+        summary.fields().get("span"),
+        Some(&Value::Str("request".to_owned()))
+    );
+    assert_eq!(summary.fields().get("suppressed"), Some(&Value::U64(2)));
+}
@@ -0,0 +1,134 @@
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, info_span, subscriber, warn, Level};
+use tracing_concat::{ConcatRecord, ConcatSink, RoutingSink, TracingConcat};
+
+/// Where each record went, as `"<sink>: <root span name>"`.
+type Routed = Arc<Mutex<Vec<String>>>;
+
+/// Returns a sink which notes each record it receives in `routed`, as `to`.
+fn sink(routed: &Routed, to: &str) -> impl ConcatSink {
+    let (routed, to) = (routed.clone(), to.to_owned());
+    move |record: &ConcatRecord| {
+        let note = format!("{}: {}", to, record.root().name());
+        routed.lock().unwrap().push(note);
+    }
+}
+
+/// Runs `f` with records flushed to `routing`.
+fn route(routing: RoutingSink, f: impl FnOnce()) {
+    let concat = TracingConcat::builder().with_sink(routing).build();
+    subscriber::with_default(concat, f);
+}
+
+fn notes(routed: &Routed) -> Vec<String> {
+    routed.lock().unwrap().clone()
+}
+
+#[test]
+fn routes_by_field() {
+    let routed = Routed::default();
+    let routing = RoutingSink::by_field("user")
+        .route("alice", sink(&routed, "alice"))
+        .route("bob", sink(&routed, "bob"))
+        .route(7, sink(&routed, "seven"))
+        .fallback(sink(&routed, "fallback"));
+    route(routing, || {
+        info_span!("alice's", user = "alice").in_scope(|| info!("handled"));
+        info_span!("bob's", user = "bob").in_scope(|| info!("handled"));
+        info_span!("carol's", user = "carol").in_scope(|| info!("handled"));
+        info_span!("numbered", user = 7).in_scope(|| info!("handled"));
+        info_span!("anonymous").in_scope(|| info!("handled"));
+        // The field is looked up on descendants if the root doesn't have it.
+        info_span!("nested").in_scope(|| {
+            info_span!("login", user = "bob").in_scope(|| info!("logged in"));
+        });
+    });
+    assert_eq!(
+        notes(&routed),
+        [
+            "alice: alice's",
+            "bob: bob's",
+            "fallback: carol's",
+            "seven: numbered",
+            "fallback: anonymous",
+            "bob: nested",
+        ]
+    );
+}
+
+#[test]
+fn routes_by_level() {
+    let routed = Routed::default();
+    let routing = RoutingSink::by_level()
+        .route(Level::ERROR, sink(&routed, "errors"))
+        .route(Level::INFO, sink(&routed, "info"));
+    route(routing, || {
+        info_span!("failed").in_scope(|| {
+            info!("started");
+            error!("failed");
+        });
+        info_span!("quiet").in_scope(|| {});
+        // Without a fallback, records which have no route are dropped.
+        info_span!("warned").in_scope(|| warn!("slow"));
+    });
+    assert_eq!(notes(&routed), ["errors: failed", "info: quiet"]);
+}
+
+#[test]
+fn route_with_makes_a_sink_once_per_key() {
+    let routed = Routed::default();
+    let made = Arc::new(Mutex::new(Vec::new()));
+    let make_route = {
+        let (routed, made) = (routed.clone(), made.clone());
+        move |key: &str| -> Option<Box<dyn ConcatSink>> {
+            made.lock().unwrap().push(key.to_owned());
+            if key == "ignored" {
+                return None;
+            }
+            Some(Box::new(sink(&routed, key)))
+        }
+    };
+    let routing = RoutingSink::by_field("tenant")
+        .route_with(make_route)
+        .route("fixed", sink(&routed, "fixed"))
+        .fallback(sink(&routed, "fallback"));
+    route(routing, || {
+        for tenant in &["a", "b", "a", "fixed", "ignored", "b"] {
+            info_span!("request", tenant = *tenant).in_scope(|| info!("handled"));
+        }
+    });
+    assert_eq!(
+        notes(&routed),
+        [
+            "a: request",
+            "b: request",
+            "a: request",
+            "fixed: request",
+            "fallback: request",
+            "b: request",
+        ]
+    );
+    assert_eq!(*made.lock().unwrap(), ["a", "b", "ignored"]);
+}
+
+#[test]
+fn tees_copy_severe_records_wherever_they_are_routed() {
+    let routed = Routed::default();
+    let routing = RoutingSink::by_field("user")
+        .route("alice", sink(&routed, "alice"))
+        .tee(Level::WARN, sink(&routed, "alerts"));
+    route(routing, || {
+        info_span!("warned", user = "alice").in_scope(|| warn!("slow"));
+        info_span!("fine", user = "alice").in_scope(|| info!("handled"));
+        info_span!("failed", user = "bob").in_scope(|| error!("failed"));
+    });
+    assert_eq!(
+        notes(&routed),
+        [
+            "alice: warned",
+            "alerts: warned",
+            "alice: fine",
+            "alerts: failed",
+        ]
+    );
+}
@@ -0,0 +1,87 @@
+use tracing::{dispatcher, info, info_span, Dispatch};
+use tracing_concat::{ConcatRecord, Stats, TracingConcat};
+
+mod common;
+use common::collecting;
+
+/// A trace ID which is kept when sampling at half the traces.
+const KEPT: &str = "4bf92f3577b34da60000000000000001";
+/// A trace ID which is dropped when sampling at half the traces.
+const DROPPED: &str = "4bf92f3577b34da6ffffffffffffffff";
+
+/// Runs `f` with `concat`, and returns the records it flushed and its
+/// statistics.
+fn sample(concat: tracing_concat::Builder, f: impl FnOnce()) -> (Vec<ConcatRecord>, Stats) {
+    let (concat, records) = collecting(concat);
+    let dispatch = Dispatch::new(concat);
+    dispatcher::with_default(&dispatch, f);
+    let stats = dispatch.downcast_ref::<TracingConcat>().unwrap().stats();
+    let records = records.lock().unwrap().clone();
+    (records, stats)
+}
+
+fn request(trace_id: &str) {
+    info_span!("request", trace_id).in_scope(|| {
+        info!("handling");
+        info_span!("db").in_scope(|| info!("queried"));
+    });
+}
+
+#[test]
+fn unsampled_trees_record_and_flush_nothing() {
+    let (records, stats) = sample(TracingConcat::builder().with_sample_rate(0.5), || {
+        request(DROPPED)
+    });
+    assert!(records.is_empty(), "{:#?}", records);
+    assert_eq!(stats.records_sampled_out(), 1);
+    assert_eq!(stats.events_sampled_out(), 2);
+    assert_eq!(stats.live_spans(), 0);
+}
+
+#[test]
+fn kept_records_carry_the_sample_rate() {
+    let (records, stats) = sample(TracingConcat::builder().with_sample_rate(0.5), || {
+        request(KEPT)
+    });
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.sample_rate(), Some(0.5));
+    assert_eq!(record.trace_id().to_string(), KEPT);
+    assert_eq!(record.root().events().len(), 1);
+    assert_eq!(record.root().children()[0].events().len(), 1);
+    assert_eq!(stats.records_sampled_out(), 0);
+}
+
+#[test]
+fn span_sample_rates_override_the_default() {
+    let builder = TracingConcat::builder()
+        .with_sample_rate(1.0)
+        .with_span_sample_rate("health", 0.0);
+    let (records, stats) = sample(builder, || {
+        info_span!("health").in_scope(|| info!("ok"));
+        request(DROPPED);
+    });
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].root().name(), "request");
+    assert_eq!(records[0].sample_rate(), Some(1.0));
+    assert_eq!(stats.records_sampled_out(), 1);
+}
+
+#[test]
+fn records_are_not_sampled_by_default() {
+    let (records, _) = sample(TracingConcat::builder(), || request(DROPPED));
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].sample_rate(), None);
+}
+
+#[test]
+fn trace_id_recorded_later_keeps_the_earlier_decision() {
+    let (records, _) = sample(TracingConcat::builder().with_sample_rate(0.5), || {
+        let span = info_span!("request", trace_id = KEPT);
+        span.in_scope(|| info!("handling"));
+        span.record("trace_id", DROPPED);
+    });
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].trace_id().to_string(), DROPPED);
+    assert_eq!(records[0].sample_rate(), Some(0.5));
+}
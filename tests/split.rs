@@ -0,0 +1,169 @@
+use serde_json::Value;
+use tracing::{error, info, info_span, subscriber, warn};
+use tracing_concat::{format::Json, RoutingSink, TracingConcat, WriterSink};
+
+mod common;
+use common::Buffer;
+
+/// Runs `f` with records split at `max` bytes and written as JSON, and
+/// returns the lines written.
+fn split(max: usize, f: impl FnOnce()) -> Vec<String> {
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let concat = TracingConcat::builder()
+        .with_sink(WriterSink::new(Json, move || writer.clone()))
+        .with_max_record_bytes(max)
+        .build();
+    subscriber::with_default(concat, f);
+    buffer.lines().into_iter().map(|line| line + "\n").collect()
+}
+
+fn parse(lines: &[String]) -> Vec<Value> {
+    lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+/// Returns the messages of the events in a JSON span and its descendants.
+fn messages(span: &Value) -> Vec<String> {
+    let mut messages = span["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["fields"]["message"].as_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    for child in span["children"].as_array().unwrap() {
+        messages.extend(self::messages(child));
+    }
+    messages
+}
+
+fn request() {
+    let span = info_span!("request", user = "someone");
+    let _span = span.enter();
+    for i in 0..10 {
+        info!("request event number {} with some padding", i);
+    }
+    info_span!("db", table = "users").in_scope(|| {
+        for i in 0..10 {
+            info!("db event number {} with some padding", i);
+        }
+    });
+}
+
+#[test]
+fn parts_fit_in_the_written_format() {
+    let lines = split(600, request);
+    assert!(lines.len() > 2, "{:#?}", lines);
+    for line in &lines {
+        assert!(line.len() <= 600, "{} bytes: {}", line.len(), line);
+    }
+
+    let parts = parse(&lines);
+    let record_id = &parts[0]["record_id"];
+    for (i, part) in parts.iter().enumerate() {
+        assert_eq!(&part["record_id"], record_id);
+        assert_eq!(part["part"], i + 1);
+        assert_eq!(part["parts"], parts.len());
+        assert_eq!(part["trace_id"], parts[0]["trace_id"]);
+    }
+}
+
+#[test]
+fn parts_hold_every_event_once_with_their_ancestors() {
+    let parts = parse(&split(600, request));
+
+    let messages = parts.iter().flat_map(messages).collect::<Vec<_>>();
+    let expected = (0..10)
+        .map(|i| format!("request event number {} with some padding", i))
+        .chain((0..10).map(|i| format!("db event number {} with some padding", i)))
+        .collect::<Vec<_>>();
+    assert_eq!(messages, expected);
+
+    let mut db_fields = 0;
+    for (i, part) in parts.iter().enumerate() {
+        // The root and its fields are in every part.
+        assert_eq!(part["name"], "request");
+        assert_eq!(part["span_id"], parts[0]["span_id"]);
+        assert_eq!(part["fields"]["user"], "someone", "part {}", i + 1);
+
+        // `db` is only in parts holding some of it, and its fields only in
+        // the first of those.
+        for child in part["children"].as_array().unwrap() {
+            assert_eq!(child["name"], "db");
+            if !child["fields"]["table"].is_null() {
+                db_fields += 1;
+            } else {
+                assert!(!child["events"].as_array().unwrap().is_empty());
+            }
+        }
+    }
+    assert_eq!(db_fields, 1);
+}
+
+#[test]
+fn oversized_event_gets_a_part_of_its_own() {
+    let big = "x".repeat(2000);
+    let lines = split(600, || {
+        info_span!("request").in_scope(|| {
+            info!("before");
+            info!("{}", big);
+            info!("after");
+        })
+    });
+    let parts = parse(&lines);
+    assert_eq!(parts.len(), 3, "{:#?}", lines);
+    assert_eq!(messages(&parts[0]), ["before"]);
+    assert_eq!(messages(&parts[1]), [big.as_str()]);
+    assert_eq!(messages(&parts[2]), ["after"]);
+    assert!(lines[1].len() > 600);
+    assert!(lines[0].len() <= 600 && lines[2].len() <= 600);
+}
+
+#[test]
+fn small_records_are_not_split() {
+    let lines = split(100_000, request);
+    assert_eq!(lines.len(), 1);
+    let record = &parse(&lines)[0];
+    assert!(record["part"].is_null());
+    assert_eq!(messages(record).len(), 20);
+}
+
+#[test]
+fn parts_are_routed_by_the_root_fields() {
+    let (routed, fallback) = (Buffer::default(), Buffer::default());
+    let (to_routed, to_fallback) = (routed.clone(), fallback.clone());
+    let routing = RoutingSink::by_field("user")
+        .route("someone", WriterSink::new(Json, move || to_routed.clone()))
+        .fallback(WriterSink::new(Json, move || to_fallback.clone()));
+    let concat = TracingConcat::builder()
+        .with_sink(routing)
+        .with_max_record_bytes(600)
+        .build();
+    subscriber::with_default(concat, request);
+
+    let lines = routed.lines();
+    assert!(lines.len() > 2, "{:#?}", lines);
+    assert_eq!(parse(&lines).len(), parse(&lines)[0]["parts"]);
+    assert!(fallback.lines().is_empty(), "{:#?}", fallback.lines());
+}
+
+#[test]
+fn parts_have_the_level_and_counts_of_the_whole_record() {
+    let parts = parse(&split(600, || {
+        info_span!("request").in_scope(|| {
+            warn!("slow start");
+            for i in 0..20 {
+                info!("request event number {} with some padding", i);
+            }
+            error!("failed");
+        })
+    }));
+    assert!(parts.len() > 2, "{:#?}", parts);
+    for part in &parts {
+        assert_eq!(part["level"], "ERROR");
+        assert_eq!(part["warn_count"], 1);
+        assert_eq!(part["error_count"], 1);
+    }
+}
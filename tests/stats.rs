@@ -0,0 +1,63 @@
+use std::time::Duration;
+use tracing::{debug, dispatcher, info, info_span, Dispatch, Level};
+use tracing_concat::{Stats, TracingConcat};
+
+mod common;
+use common::collecting;
+
+fn stats(dispatch: &Dispatch) -> Stats {
+    dispatch.downcast_ref::<TracingConcat>().unwrap().stats()
+}
+
+#[test]
+fn stats_count_a_known_workload() {
+    let builder = TracingConcat::builder()
+        .with_max_level(Level::INFO)
+        .with_span_sample_rate("health", 0.0)
+        .with_rate_limit("burst", 1, Duration::from_secs(60 * 60));
+    let (concat, records) = collecting(builder);
+    let dispatch = Dispatch::new(concat);
+
+    dispatcher::with_default(&dispatch, || {
+        info!("outside");
+        info_span!("health").in_scope(|| info!("ok"));
+        for _ in 0..2 {
+            info_span!("burst").in_scope(|| {});
+        }
+
+        let root = info_span!("root", user = "ab");
+        let _root = root.enter();
+        info!("one");
+        debug!("filtered");
+        info_span!("db").in_scope(|| info!("two"));
+
+        let stats = stats(&dispatch);
+        assert_eq!(stats.events_outside_spans(), 1);
+        assert_eq!(stats.events_filtered(), 1);
+        assert_eq!(stats.events_sampled_out(), 1);
+        assert_eq!(stats.records_sampled_out(), 1);
+        assert_eq!(stats.records_rate_limited(), 1);
+        assert_eq!(stats.records_flushed(), 1);
+        assert_eq!(stats.live_spans(), 1);
+        // At most two spans were open at once, and `db` has closed.
+        assert_eq!(stats.free_slots(), 1);
+        assert!(stats.slab_capacity() >= 2);
+        // `one`, and `two` in the closed `db`.
+        assert_eq!(stats.buffered_events(), 2);
+        // The root's `user` field is 4 + 2 + 4 bytes, each event 32 bytes
+        // plus its target and `message` field, and `db` 32 bytes plus its
+        // name.
+        let event = 32 + "stats".len() + "message".len() + 3 + 4;
+        assert_eq!(stats.buffered_bytes(), 10 + event + 32 + 2 + event);
+        assert_eq!(stats.span_misuses(), 0);
+        assert_eq!(stats.lock_poison_recoveries(), 0);
+    });
+
+    let stats = stats(&dispatch);
+    assert_eq!(stats.records_flushed(), 2);
+    assert_eq!(records.lock().unwrap().len(), 2);
+    assert_eq!(stats.live_spans(), 0);
+    assert_eq!(stats.free_slots(), 2);
+    assert_eq!(stats.buffered_events(), 0);
+    assert_eq!(stats.buffered_bytes(), 0);
+}
@@ -0,0 +1,34 @@
+use tracing::{info, info_span, subscriber};
+use tracing_concat::TracingConcat;
+
+mod common;
+use common::collecting;
+
+#[test]
+fn tree_lines_have_span_ids() {
+    let (concat, records) = collecting(TracingConcat::builder());
+
+    subscriber::with_default(concat, || {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        info_span!("request", %traceparent).in_scope(|| {
+            info_span!("db").in_scope(|| info!("queried"));
+        });
+    });
+
+    let records = records.lock().unwrap();
+    let record = &records[0];
+    let tree = record.to_string();
+    let mut lines = tree.lines();
+
+    let root = lines.next().unwrap();
+    assert!(root.starts_with("request{"), "{}", root);
+    assert!(root.contains(&format!(" span_id={} ", record.root().id())));
+    assert!(root.contains(" trace_id=4bf92f3577b34da6a3ce929d0e0e4736 "));
+    assert!(root.contains(" parent_span_id=00f067aa0ba902b7"));
+
+    let db = lines.next().unwrap();
+    let child = &record.root().children()[0];
+    assert!(db.starts_with("  db "), "{}", db);
+    assert!(db.ends_with(&format!(" span_id={}", child.id())), "{}", db);
+    assert!(!db.contains("parent_span_id"));
+}